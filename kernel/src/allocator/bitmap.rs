@@ -0,0 +1,281 @@
+use limine::LimineMemoryMapEntryType;
+use x86::current::paging::{PAddr, BASE_PAGE_SIZE};
+
+use super::{memory_map, FrameAllocator, FrameStats};
+use crate::arch::PhysicalAddress;
+
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+/// A bitmap based physical frame allocator.
+///
+/// Every physical frame below the highest usable address is represented by a
+/// single bit, a set bit means the frame is in use (or not usable at all) and a
+/// cleared bit means the frame is free.
+///
+/// The bitmap itself is placed inside of the first usable region that is large
+/// enough to hold it.
+///
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    frame_count: usize,
+
+    total_frames: usize,
+    free_frames: usize,
+
+    /// Every frame below this index is known to be in use.
+    next_free: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Creates a new `BitmapFrameAllocator` from the limine memory map.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because it should only be called once,
+    /// calling it twice would hand out the same frames twice.
+    ///
+    pub unsafe fn new() -> Self {
+        let memmap = memory_map();
+
+        let usable = || {
+            memmap
+                .iter()
+                .filter(|entry| entry.typ == LimineMemoryMapEntryType::Usable)
+        };
+
+        // Bootloader reclaimable memory is handed to us later on, so the bitmap
+        // has to cover it as well
+        let highest_address = memmap
+            .iter()
+            .filter(|entry| {
+                entry.typ == LimineMemoryMapEntryType::Usable
+                    || entry.typ == LimineMemoryMapEntryType::BootloaderReclaimable
+            })
+            .map(|entry| entry.base + entry.len)
+            .max()
+            .expect("No usable memory available!") as usize;
+
+        let frame_count = highest_address / BASE_PAGE_SIZE;
+        let bitmap_words = (frame_count + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        let bitmap_size = bitmap_words * core::mem::size_of::<u64>();
+        let bitmap_frames = (bitmap_size + BASE_PAGE_SIZE - 1) / BASE_PAGE_SIZE;
+
+        let bitmap_base = usable()
+            .find(|entry| entry.len as usize >= bitmap_frames * BASE_PAGE_SIZE)
+            .expect("No usable region is large enough to hold the frame bitmap!")
+            .base as usize;
+
+        let bitmap = core::slice::from_raw_parts_mut(
+            PhysicalAddress::new(bitmap_base as u64)
+                .to_virtual()
+                .as_mut_ptr(),
+            bitmap_words,
+        );
+
+        // Everything is considered used until the memory map tells us otherwise
+        bitmap.fill(u64::MAX);
+
+        let mut allocator = Self {
+            bitmap,
+            frame_count,
+            total_frames: 0,
+            free_frames: 0,
+            next_free: frame_count,
+        };
+
+        for (i, entry) in usable().enumerate() {
+            // Usable entries are guaranteed to be page aligned by the limine spec
+            let first = entry.base as usize / BASE_PAGE_SIZE;
+            let count = entry.len as usize / BASE_PAGE_SIZE;
+
+            allocator.add_range(first, count);
+
+            trace!(
+                "Region {}: {:#x} - {:#x} ({} pages, {} bytes)",
+                i,
+                entry.base,
+                entry.base + entry.len,
+                count,
+                entry.len
+            );
+        }
+
+        // The bitmap must not hand out its own storage
+        allocator.reserve(bitmap_base / BASE_PAGE_SIZE, bitmap_frames);
+
+        // Never hand out the zero frame, it is indistinguishable from a null pointer
+        if !allocator.is_used(0) {
+            allocator.reserve(0, 1);
+        }
+
+        trace!(
+            "BitmapFrameAllocator: bitmap at {:#x} ({} pages) tracking {} frames",
+            bitmap_base,
+            bitmap_frames,
+            frame_count
+        );
+
+        let stats = allocator.stats();
+
+        trace!(
+            "BitmapFrameAllocator: {} bytes available ({:.2} MiB, {:.2} GiB, {} pages)",
+            stats.free_bytes(),
+            stats.free_bytes() as f64 / 1024.0 / 1024.0,
+            stats.free_bytes() as f64 / 1024.0 / 1024.0 / 1024.0,
+            stats.free_frames
+        );
+
+        allocator
+    }
+
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / BITS_PER_WORD] & (1 << (frame % BITS_PER_WORD)) != 0
+    }
+
+    fn set_range(&mut self, first: usize, count: usize, used: bool) {
+        for frame in first..first + count {
+            let word = &mut self.bitmap[frame / BITS_PER_WORD];
+            let bit = 1 << (frame % BITS_PER_WORD);
+
+            if used {
+                *word |= bit;
+            } else {
+                *word &= !bit;
+            }
+        }
+    }
+
+    /// Adds a range of frames that were previously not managed by the allocator.
+    fn add_range(&mut self, first: usize, count: usize) {
+        self.set_range(first, count, false);
+        self.total_frames += count;
+        self.free_frames += count;
+        self.next_free = self.next_free.min(first);
+    }
+
+    /// Hands all bootloader reclaimable memory over to the allocator.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may access bootloader reclaimable memory afterwards, this includes
+    /// every limine response, the bootloader's page tables and its stack.
+    ///
+    pub unsafe fn reclaim_bootloader_memory(&mut self) {
+        let entries = memory_map()
+            .iter()
+            .filter(|entry| entry.typ == LimineMemoryMapEntryType::BootloaderReclaimable);
+
+        for entry in entries {
+            let first = entry.base as usize / BASE_PAGE_SIZE;
+            let count = entry.len as usize / BASE_PAGE_SIZE;
+
+            trace!(
+                "Reclaiming {:#x} - {:#x} ({} pages)",
+                entry.base,
+                entry.base + entry.len,
+                count
+            );
+
+            self.add_range(first, count);
+        }
+    }
+
+    /// Marks a range of free frames as used without handing them out.
+    fn reserve(&mut self, first: usize, count: usize) {
+        self.set_range(first, count, true);
+        self.free_frames -= count;
+    }
+
+    /// Finds the first run of `count` free frames starting at a multiple of `align` frames.
+    fn find_free_run(&self, count: usize, align: usize) -> Option<usize> {
+        let mut frame = align_up(self.next_free, align);
+
+        while frame + count <= self.frame_count {
+            // Skip over fully used words, this keeps the common case fast
+            if frame % BITS_PER_WORD == 0 && self.bitmap[frame / BITS_PER_WORD] == u64::MAX {
+                frame = align_up(frame + BITS_PER_WORD, align);
+                continue;
+            }
+
+            match (frame..frame + count).find(|&f| self.is_used(f)) {
+                Some(used) => frame = align_up(used + 1, align),
+                None => return Some(frame),
+            }
+        }
+
+        None
+    }
+}
+
+impl FrameAllocator for BitmapFrameAllocator {
+    fn alloc_aligned(&mut self, count: usize, align: usize) -> Option<PAddr> {
+        assert!(count > 0, "Cannot allocate zero frames");
+        assert!(
+            align.is_power_of_two() && align >= BASE_PAGE_SIZE,
+            "Invalid frame alignment {:#x}",
+            align
+        );
+
+        if count > self.free_frames {
+            return None;
+        }
+
+        let Some(first) = self.find_free_run(count, align / BASE_PAGE_SIZE) else {
+            trace!(
+                "BitmapFrameAllocator: Out of memory! ({} pages, {:#x} alignment)",
+                count,
+                align
+            );
+            return None;
+        };
+
+        self.set_range(first, count, true);
+        self.free_frames -= count;
+
+        if first == self.next_free {
+            self.next_free = first + count;
+        }
+
+        Some(PAddr::from(first * BASE_PAGE_SIZE))
+    }
+
+    fn dealloc(&mut self, frame: PAddr, count: usize) {
+        assert!(
+            frame.is_base_page_aligned(),
+            "Freeing unaligned frame {:#x}",
+            frame
+        );
+
+        let first = frame.as_usize() / BASE_PAGE_SIZE;
+        assert!(
+            first + count <= self.frame_count,
+            "Freeing frames {:#x} ({} pages) outside of managed memory",
+            frame,
+            count
+        );
+
+        for f in first..first + count {
+            assert!(
+                self.is_used(f),
+                "Double free of frame {:#x}",
+                f * BASE_PAGE_SIZE
+            );
+        }
+
+        self.set_range(first, count, false);
+        self.free_frames += count;
+        self.next_free = self.next_free.min(first);
+    }
+
+    fn stats(&self) -> FrameStats {
+        FrameStats {
+            total_frames: self.total_frames,
+            free_frames: self.free_frames,
+            used_frames: self.total_frames - self.free_frames,
+        }
+    }
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
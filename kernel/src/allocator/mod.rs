@@ -1,18 +1,70 @@
-use core::alloc::{GlobalAlloc, Layout};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr,
+};
 
+use limine::{LimineMemmapEntry, LimineMemmapRequest, NonNullPtr};
+use spin::Mutex;
 use x86::current::paging::{PAddr, BASE_PAGE_SIZE};
 
-use self::bump::BumpAllocator;
+use self::{bitmap::BitmapFrameAllocator, slab::SlabAllocator};
+use crate::arch::{x86_64::irq::without_interrupts, PhysicalAddress, VirtualAddress};
 
-mod bump;
+mod bitmap;
+mod slab;
 
 pub trait FrameAllocator {
-    /// Allocates a frame of the given size and alignment.
+    /// Allocates contiguous frames.
     ///
     /// # Arguments
     /// * `count`: The number of frames to allocate.
     ///
-    fn alloc(&mut self, count: usize) -> Option<PAddr>;
+    fn alloc(&mut self, count: usize) -> Option<PAddr> {
+        self.alloc_aligned(count, BASE_PAGE_SIZE)
+    }
+
+    /// Allocates contiguous frames where the first frame is aligned.
+    ///
+    /// # Arguments
+    /// * `count`: The number of frames to allocate.
+    /// * `align`: The alignment of the first frame in bytes, must be a power of two
+    ///            and at least `BASE_PAGE_SIZE`.
+    ///
+    fn alloc_aligned(&mut self, count: usize, align: usize) -> Option<PAddr>;
+
+    /// Returns frames previously handed out by `alloc` or `alloc_aligned`.
+    ///
+    /// # Arguments
+    /// * `frame`: The first frame to free.
+    /// * `count`: The number of frames to free.
+    ///
+    fn dealloc(&mut self, frame: PAddr, count: usize);
+
+    /// Returns the current usage statistics of the allocator.
+    ///
+    fn stats(&self) -> FrameStats;
+}
+
+/// Usage statistics of a `FrameAllocator`, all values are in frames.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    pub total_frames: usize,
+    pub used_frames: usize,
+    pub free_frames: usize,
+}
+
+impl FrameStats {
+    pub fn total_bytes(&self) -> usize {
+        self.total_frames * BASE_PAGE_SIZE
+    }
+
+    pub fn used_bytes(&self) -> usize {
+        self.used_frames * BASE_PAGE_SIZE
+    }
+
+    pub fn free_bytes(&self) -> usize {
+        self.free_frames * BASE_PAGE_SIZE
+    }
 }
 
 pub(super) fn init() {
@@ -26,60 +78,176 @@ pub(super) fn init() {
         IS_INITIALIZED = true;
     }
 
-    let bump = unsafe { BumpAllocator::new() };
-    unsafe {
-        HEAP.init(bump);
-    }
+    let frame_allocator = unsafe { BitmapFrameAllocator::new() };
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+
+    let stats = frame_stats();
+    info!(
+        "Physical memory: {} KiB used, {} KiB free, {} KiB total",
+        stats.used_bytes() / 1024,
+        stats.free_bytes() / 1024,
+        stats.total_bytes() / 1024
+    );
 
     info!("Initialized heap");
 }
 
-#[global_allocator]
-pub static mut HEAP: Heap = Heap::new();
+static MEMMAP_REQUEST: LimineMemmapRequest = LimineMemmapRequest::new(0);
+
+/// Returns the memory map provided by limine.
+///
+/// The memory map lives in bootloader reclaimable memory, so it must not be
+/// used after `reclaim_bootloader_memory` has been called.
+///
+pub fn memory_map() -> &'static [NonNullPtr<LimineMemmapEntry>] {
+    MEMMAP_REQUEST
+        .get_response()
+        .get()
+        .expect("Bootloader did not provide a memory map")
+        .memmap()
+}
 
-pub struct Heap {
-    // FIXME: Use a more sophisticated allocator.
-    //        a bump allocator is really simple but also huge memory leaks
-    //        since we cannot reclaim memory
+/// Returns all bootloader reclaimable memory to the frame allocator.
+///
+/// # Safety
+///
+/// Nothing may access bootloader reclaimable memory afterwards, this includes
+/// every limine response, the bootloader's page tables and its stack.
+///
+pub unsafe fn reclaim_bootloader_memory() {
+    let (before, after) = without_interrupts(|| {
+        let mut frames = FRAME_ALLOCATOR.lock();
+        let frames = frames.as_mut().unwrap();
+
+        let before = frames.stats();
+        frames.reclaim_bootloader_memory();
+
+        (before, frames.stats())
+    });
+
+    info!(
+        "Reclaimed {} KiB of bootloader memory",
+        (after.free_bytes() - before.free_bytes()) / 1024
+    );
+}
+
+static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
 
-    // FIXME: given that rust is more optimized for a slab allocator, we should
-    //        probably use that instead
-    pub allocator: Option<BumpAllocator>,
+#[global_allocator]
+static HEAP: Heap = Heap::new();
+
+/// The kernel heap.
+///
+/// Small allocations are served by a `SlabAllocator`, everything larger than
+/// `MAX_SLAB_OBJECT_SIZE` is allocated as whole frames from the frame allocator.
+///
+/// Running out of memory returns a null pointer, which makes `alloc` invoke
+/// the allocation error handler (or lets fallible APIs like `try_reserve` fail).
+///
+pub struct Heap {
+    slab: Mutex<SlabAllocator>,
 }
 
 impl Heap {
     pub const fn new() -> Self {
-        Heap { allocator: None }
+        Heap {
+            slab: Mutex::new(SlabAllocator::new()),
+        }
     }
 
-    pub unsafe fn init(&mut self, allocator: BumpAllocator) {
-        self.allocator = Some(allocator);
+    unsafe fn alloc_locked(&self, layout: Layout) -> *mut u8 {
+        let mut frames = FRAME_ALLOCATOR.lock();
+        let frames = frames.as_mut().expect("Heap used before initialization");
+
+        let ptr = match SlabAllocator::size_class(layout) {
+            Some(class) => self.slab.lock().alloc(class, frames),
+            None => {
+                let frame_count = (layout.size() + BASE_PAGE_SIZE - 1) / BASE_PAGE_SIZE;
+                let align = layout.align().max(BASE_PAGE_SIZE);
+
+                frames
+                    .alloc_aligned(frame_count, align)
+                    .map_or(ptr::null_mut(), |frame| {
+                        PhysicalAddress::from(frame).to_virtual().as_mut_ptr()
+                    })
+            }
+        };
+
+        if ptr.is_null() {
+            let stats = frames.stats();
+            error!(
+                "Heap: Out of memory! (size: {}, align: {}, {} KiB free)",
+                layout.size(),
+                layout.align(),
+                stats.free_bytes() / 1024
+            );
+        }
+
+        ptr
     }
-}
 
-unsafe impl GlobalAlloc for Heap {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        // we assume that the heap is initialized
-        let allocator = HEAP.allocator.as_mut().unwrap();
+    unsafe fn dealloc_locked(&self, ptr: *mut u8, layout: Layout) {
+        let mut frames = FRAME_ALLOCATOR.lock();
+        let frames = frames.as_mut().expect("Heap used before initialization");
 
-        // The BumpAllocator can only allocate in frames, so we need to figure out the count of
-        // frames we need to allocate.
+        match SlabAllocator::size_class(layout) {
+            Some(class) => self.slab.lock().dealloc(ptr, class, frames),
+            None => {
+                let frame_count = (layout.size() + BASE_PAGE_SIZE - 1) / BASE_PAGE_SIZE;
 
-        let frame_count = (layout.size() + BASE_PAGE_SIZE - 1) / BASE_PAGE_SIZE;
-        let frame = allocator.alloc(frame_count).unwrap_or(PAddr::zero());
+                let frame = VirtualAddress::from(ptr).to_physical();
+                frames.dealloc(frame.into(), frame_count);
+            }
+        }
+    }
+}
 
-        frame.0 as *mut u8
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // The scheduler allocates with interrupts disabled, the lock must
+        // never be held by a thread that gets preempted
+        without_interrupts(|| self.alloc_locked(layout))
     }
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
-        // bump allocator does not support dealloc
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| self.dealloc_locked(ptr, layout))
     }
 }
 
 pub unsafe fn allocate_pages(count: usize) -> PAddr {
-    HEAP.allocator.as_mut().unwrap().alloc(count).unwrap()
+    try_allocate_pages(count).unwrap()
+}
+
+/// Like `allocate_pages`, but returns `None` instead of panicking when there
+/// are not enough free frames.
+///
+pub unsafe fn try_allocate_pages(count: usize) -> Option<PAddr> {
+    without_interrupts(|| FRAME_ALLOCATOR.lock().as_mut().unwrap().alloc(count))
 }
 
 pub unsafe fn deallocate_pages(addr: PAddr, count: usize) {
-    unimplemented!("deallocate_pages")
+    without_interrupts(|| {
+        FRAME_ALLOCATOR
+            .lock()
+            .as_mut()
+            .unwrap()
+            .dealloc(addr, count)
+    })
+}
+
+pub unsafe fn allocate_aligned_pages(count: usize, align: usize) -> PAddr {
+    without_interrupts(|| {
+        FRAME_ALLOCATOR
+            .lock()
+            .as_mut()
+            .unwrap()
+            .alloc_aligned(count, align)
+            .unwrap()
+    })
+}
+
+/// Returns the usage statistics of the physical frame allocator.
+///
+pub fn frame_stats() -> FrameStats {
+    without_interrupts(|| FRAME_ALLOCATOR.lock().as_ref().unwrap().stats())
 }
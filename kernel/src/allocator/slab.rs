@@ -0,0 +1,222 @@
+use core::{alloc::Layout, mem::size_of, ptr};
+
+use x86::current::paging::BASE_PAGE_SIZE;
+
+use super::FrameAllocator;
+use crate::arch::{PhysicalAddress, VirtualAddress};
+
+/// The object sizes served by the slab allocator, everything larger goes
+/// straight to the frame allocator.
+const SIZE_CLASSES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// The largest object size served by the slab allocator.
+pub const MAX_SLAB_OBJECT_SIZE: usize = SIZE_CLASSES[SIZE_CLASSES.len() - 1];
+
+const SLAB_FRAMES: usize = 8;
+
+/// The size of a single slab, slabs are aligned to their size so the owning
+/// slab of an object can be found by masking its address.
+const SLAB_SIZE: usize = SLAB_FRAMES * BASE_PAGE_SIZE;
+
+/// A free object, stored inside of the object itself.
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// The header of a slab, located at the very beginning of the slab.
+///
+/// The objects follow the header, starting at the first offset that is a
+/// multiple of the object size so every object is naturally aligned.
+#[repr(C)]
+struct Slab {
+    next: *mut Slab,
+    prev: *mut Slab,
+
+    free_list: *mut FreeObject,
+    in_use: usize,
+    capacity: usize,
+}
+
+/// A cache of slabs for a single size class.
+struct SlabCache {
+    object_size: usize,
+
+    /// Slabs which have at least one free object.
+    partial: *mut Slab,
+    partial_count: usize,
+}
+
+impl SlabCache {
+    const fn new(object_size: usize) -> Self {
+        Self {
+            object_size,
+            partial: ptr::null_mut(),
+            partial_count: 0,
+        }
+    }
+
+    unsafe fn push_partial(&mut self, slab: *mut Slab) {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = self.partial;
+
+        if !self.partial.is_null() {
+            (*self.partial).prev = slab;
+        }
+
+        self.partial = slab;
+        self.partial_count += 1;
+    }
+
+    unsafe fn remove_partial(&mut self, slab: *mut Slab) {
+        if (*slab).prev.is_null() {
+            self.partial = (*slab).next;
+        } else {
+            (*(*slab).prev).next = (*slab).next;
+        }
+
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+
+        self.partial_count -= 1;
+    }
+
+    /// Allocates a new slab and carves it into objects.
+    unsafe fn grow(&mut self, frames: &mut impl FrameAllocator) -> Option<*mut Slab> {
+        let frame = frames.alloc_aligned(SLAB_FRAMES, SLAB_SIZE)?;
+        let slab: *mut Slab = PhysicalAddress::from(frame).to_virtual().as_mut_ptr();
+
+        let first_object = align_up(size_of::<Slab>(), self.object_size);
+        let capacity = (SLAB_SIZE - first_object) / self.object_size;
+
+        // Thread the free list through the objects in address order
+        let mut free_list = ptr::null_mut();
+        for i in (0..capacity).rev() {
+            let object = (slab as usize + first_object + i * self.object_size) as *mut FreeObject;
+            (*object).next = free_list;
+            free_list = object;
+        }
+
+        slab.write(Slab {
+            next: ptr::null_mut(),
+            prev: ptr::null_mut(),
+            free_list,
+            in_use: 0,
+            capacity,
+        });
+
+        self.push_partial(slab);
+
+        Some(slab)
+    }
+
+    unsafe fn alloc(&mut self, frames: &mut impl FrameAllocator) -> *mut u8 {
+        let slab = if self.partial.is_null() {
+            match self.grow(frames) {
+                Some(slab) => slab,
+                None => return ptr::null_mut(),
+            }
+        } else {
+            self.partial
+        };
+
+        let object = (*slab).free_list;
+        (*slab).free_list = (*object).next;
+        (*slab).in_use += 1;
+
+        // The slab is full, it has nothing to offer anymore
+        if (*slab).free_list.is_null() {
+            self.remove_partial(slab);
+        }
+
+        object as *mut u8
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, frames: &mut impl FrameAllocator) {
+        let slab = (ptr as usize & !(SLAB_SIZE - 1)) as *mut Slab;
+        let was_full = (*slab).free_list.is_null();
+
+        let object = ptr as *mut FreeObject;
+        (*object).next = (*slab).free_list;
+        (*slab).free_list = object;
+        (*slab).in_use -= 1;
+
+        if was_full {
+            self.push_partial(slab);
+        }
+
+        // Give empty slabs back, but keep one around so we don't thrash
+        // when a single object gets allocated and freed in a loop
+        if (*slab).in_use == 0 && self.partial_count > 1 {
+            self.remove_partial(slab);
+            let frame = VirtualAddress::from(slab).to_physical();
+            frames.dealloc(frame.into(), SLAB_FRAMES);
+        }
+    }
+}
+
+/// A slab allocator with power of two size classes.
+///
+/// Every size class owns a list of 32 KiB slabs, each of which is split into
+/// objects of that size. Objects are naturally aligned to their size, so an
+/// allocation is served by the smallest class that can hold both its size
+/// and alignment.
+///
+pub struct SlabAllocator {
+    caches: [SlabCache; SIZE_CLASSES.len()],
+}
+
+// SAFETY: The raw pointers only point into slabs owned by this allocator.
+unsafe impl Send for SlabAllocator {}
+
+impl SlabAllocator {
+    pub const fn new() -> Self {
+        Self {
+            caches: [
+                SlabCache::new(SIZE_CLASSES[0]),
+                SlabCache::new(SIZE_CLASSES[1]),
+                SlabCache::new(SIZE_CLASSES[2]),
+                SlabCache::new(SIZE_CLASSES[3]),
+                SlabCache::new(SIZE_CLASSES[4]),
+                SlabCache::new(SIZE_CLASSES[5]),
+                SlabCache::new(SIZE_CLASSES[6]),
+                SlabCache::new(SIZE_CLASSES[7]),
+                SlabCache::new(SIZE_CLASSES[8]),
+            ],
+        }
+    }
+
+    /// Returns the index of the size class serving the given layout, if any.
+    ///
+    pub fn size_class(layout: Layout) -> Option<usize> {
+        let size = layout.size().max(layout.align());
+
+        SIZE_CLASSES.iter().position(|&class| class >= size)
+    }
+
+    /// Allocates an object of the given size class.
+    ///
+    /// Returns a null pointer if the frame allocator ran out of memory.
+    ///
+    /// # Safety
+    ///
+    /// `class` must be obtained from `size_class`.
+    ///
+    pub unsafe fn alloc(&mut self, class: usize, frames: &mut impl FrameAllocator) -> *mut u8 {
+        self.caches[class].alloc(frames)
+    }
+
+    /// Returns an object to its size class.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been allocated by this allocator with the same `class`.
+    ///
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8, class: usize, frames: &mut impl FrameAllocator) {
+        self.caches[class].dealloc(ptr, frames)
+    }
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
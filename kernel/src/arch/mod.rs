@@ -1,21 +1,168 @@
 pub mod x86_64;
 
-pub use x86_64::hcf;
+use x86::current::paging::PAddr;
+
+use self::x86_64::mmu::hhdm_offset;
+
+pub use x86_64::{clock, hcf, modules, percpu::this_cpu, power};
 
 #[repr(transparent)]
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct PhysicalAddress(u64);
 
+impl PhysicalAddress {
+    pub const fn new(addr: u64) -> Self {
+        Self(addr)
+    }
+
+    pub const fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    /// Returns the address at which this physical address is visible in
+    /// the higher half direct map.
+    ///
+    pub fn to_virtual(self) -> VirtualAddress {
+        VirtualAddress(self.0 + hhdm_offset())
+    }
+}
+
+impl From<PAddr> for PhysicalAddress {
+    fn from(addr: PAddr) -> Self {
+        Self(addr.as_u64())
+    }
+}
+
+impl From<PhysicalAddress> for PAddr {
+    fn from(addr: PhysicalAddress) -> Self {
+        PAddr(addr.0)
+    }
+}
+
 #[repr(transparent)]
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct VirtualAddress(u64);
 
+impl VirtualAddress {
+    pub const fn new(addr: u64) -> Self {
+        Self(addr)
+    }
+
+    pub const fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    pub fn as_ptr<T>(self) -> *const T {
+        self.0 as *const T
+    }
+
+    pub fn as_mut_ptr<T>(self) -> *mut T {
+        self.0 as *mut T
+    }
+
+    /// Returns the physical address backing this address.
+    ///
+    /// This only works for addresses within the higher half direct map.
+    ///
+    pub fn to_physical(self) -> PhysicalAddress {
+        debug_assert!(
+            self.0 >= hhdm_offset(),
+            "{:#x} is not part of the higher half direct map",
+            self.0
+        );
+
+        PhysicalAddress(self.0 - hhdm_offset())
+    }
+}
+
+impl<T> From<*const T> for VirtualAddress {
+    fn from(ptr: *const T) -> Self {
+        Self(ptr as u64)
+    }
+}
+
+impl<T> From<*mut T> for VirtualAddress {
+    fn from(ptr: *mut T) -> Self {
+        Self(ptr as u64)
+    }
+}
+
+bitflags! {
+    /// The permissions and caching attributes of a mapped page.
+    ///
+    /// Every mapped page is readable, kernel only, executable and cached
+    /// unless stated otherwise.
+    ///
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PageFlags: u64 {
+        const WRITABLE = 1 << 0;
+        const USER = 1 << 1;
+        const NO_EXECUTE = 1 << 2;
+        const GLOBAL = 1 << 3;
+        const WRITE_THROUGH = 1 << 4;
+        const CACHE_DISABLE = 1 << 5;
+    }
+}
+
+/// The size of a single mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PageSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
+
+impl PageSize {
+    /// Returns the size of the page in bytes.
+    pub const fn bytes(self) -> u64 {
+        match self {
+            PageSize::Size4KiB => 4 * 1024,
+            PageSize::Size2MiB => 2 * 1024 * 1024,
+            PageSize::Size1GiB => 1024 * 1024 * 1024,
+        }
+    }
+}
+
+/// The result of translating a virtual address.
+#[derive(Debug, Clone, Copy)]
+pub struct Translation {
+    /// The physical address the virtual address translates to.
+    pub phys: PhysicalAddress,
+    /// The size of the page containing the address.
+    pub size: PageSize,
+    /// The flags of the page containing the address.
+    pub flags: PageFlags,
+}
+
 pub trait MemoryMapper {
     unsafe fn new() -> Self;
     unsafe fn from_active() -> Self;
 
-    unsafe fn map(&mut self, phys: PhysicalAddress, virt: VirtualAddress, flags: u64);
-    unsafe fn unmap(&mut self, virt: VirtualAddress);
+    /// Maps a single page, creating every missing intermediate table.
+    ///
+    /// Both addresses have to be aligned to the page size.
+    ///
+    unsafe fn map(
+        &mut self,
+        phys: PhysicalAddress,
+        virt: VirtualAddress,
+        size: PageSize,
+        flags: PageFlags,
+    );
+
+    /// Unmaps the page containing `virt`, returning what it was mapped to.
+    ///
+    unsafe fn unmap(&mut self, virt: VirtualAddress) -> Option<(PhysicalAddress, PageSize)>;
+
+    /// Translates a virtual address into the physical address it is mapped to.
+    ///
+    fn translate(&self, virt: VirtualAddress) -> Option<Translation>;
+
+    /// Changes the flags of the page containing `virt`.
+    ///
+    /// Returns `false` if the address is not mapped.
+    ///
+    unsafe fn protect(&mut self, virt: VirtualAddress, flags: PageFlags) -> bool;
 
     unsafe fn submit(&mut self);
 }
@@ -1,10 +1,26 @@
-use acpi::AcpiHandler;
+use core::ptr::NonNull;
+
+use acpi::{
+    fadt::Fadt, sdt::Signature, AcpiHandler, HpetInfo, InterruptModel, PhysicalMapping,
+    PlatformInfo,
+};
 use limine::LimineRsdpRequest;
+use spin::Mutex;
+
+use super::mmu;
+use crate::arch::{PhysicalAddress, VirtualAddress};
 
 static mut ACPI_RSDP_REQUEST: LimineRsdpRequest = LimineRsdpRequest::new(0);
 
-type AcpiTables = acpi::AcpiTables<AcpiMapper>;
+pub type AcpiTables = acpi::AcpiTables<AcpiMapper>;
 
+static ACPI_TABLES: Mutex<Option<AcpiTables>> = Mutex::new(None);
+
+/// Parses the ACPI tables the bootloader found.
+///
+/// The RSDP response lives in bootloader reclaimable memory, so this has to
+/// be called before that memory is reclaimed.
+///
 pub unsafe fn init() {
     let acpi_tables = {
         let addr = ACPI_RSDP_REQUEST
@@ -15,23 +31,138 @@ pub unsafe fn init() {
             .as_ptr()
             .unwrap();
 
-        AcpiTables::from_rsdp(AcpiMapper, addr as usize)
+        // Limine hands us the RSDP through the higher half direct map
+        let addr = VirtualAddress::from(addr).to_physical();
+
+        AcpiTables::from_rsdp(AcpiMapper, addr.as_u64() as usize)
+            .expect("Failed to parse the ACPI tables")
     };
+
+    log_summary(&acpi_tables);
+
+    *ACPI_TABLES.lock() = Some(acpi_tables);
+}
+
+/// Calls `f` with the parsed ACPI tables.
+///
+pub fn with_tables<R>(f: impl FnOnce(&AcpiTables) -> R) -> R {
+    f(ACPI_TABLES
+        .lock()
+        .as_ref()
+        .expect("ACPI tables are not parsed yet"))
+}
+
+/// Returns the interrupt model and processors described by the ACPI tables.
+///
+pub fn platform_info() -> PlatformInfo {
+    with_tables(|tables| {
+        tables
+            .platform_info()
+            .expect("Failed to gather platform info from the ACPI tables")
+    })
 }
 
+/// Logs every table the firmware provides, followed by the details of the
+/// tables the kernel cares about.
+fn log_summary(tables: &AcpiTables) {
+    info!("ACPI revision {}", tables.revision);
+
+    for (signature, sdt) in &tables.sdts {
+        trace!(
+            "  {} at {:#x} ({} bytes)",
+            signature,
+            sdt.physical_address,
+            sdt.length
+        );
+    }
+
+    match tables.platform_info() {
+        Ok(platform_info) => {
+            if let InterruptModel::Apic(apic) = &platform_info.interrupt_model {
+                info!(
+                    "  MADT: {} I/O APIC(s), {} interrupt source override(s), legacy PIC: {}",
+                    apic.io_apics.len(),
+                    apic.interrupt_source_overrides.len(),
+                    apic.also_has_legacy_pics
+                );
+            }
+
+            if let Some(processor_info) = &platform_info.processor_info {
+                info!(
+                    "  MADT: {} processor(s), BSP has APIC id {}",
+                    processor_info.application_processors.len() + 1,
+                    processor_info.boot_processor.local_apic_id
+                );
+            }
+        }
+        Err(error) => info!("  MADT: unusable ({:?})", error),
+    }
+
+    if let Ok(Some(fadt)) = unsafe { tables.get_sdt::<Fadt>(Signature::FADT) } {
+        let sci_interrupt = fadt.sci_interrupt;
+        let century = fadt.century;
+
+        info!(
+            "  FADT: {:?} profile, SCI on IRQ {}, century register {:#x}",
+            fadt.power_profile(),
+            sci_interrupt,
+            century
+        );
+    }
+
+    match HpetInfo::new(tables) {
+        Ok(hpet) => info!(
+            "  HPET: at {:#x}, {} comparator(s), 64 bit counter: {}",
+            hpet.base_address,
+            hpet.num_comparators(),
+            hpet.main_counter_is_64bits()
+        ),
+        Err(_) => info!("  HPET: not present"),
+    }
+
+    // MCFG entries are 16 bytes each and follow the header and 8 reserved bytes
+    match tables.sdts.get(&Signature::MCFG) {
+        Some(mcfg) => info!(
+            "  MCFG: {} PCIe configuration space region(s)",
+            (mcfg.length as usize).saturating_sub(44) / 16
+        ),
+        None => info!("  MCFG: not present"),
+    }
+
+    match tables.sdts.get(&Signature::SRAT) {
+        Some(srat) => info!("  SRAT: present ({} bytes)", srat.length),
+        None => info!("  SRAT: not present"),
+    }
+}
+
+/// Maps ACPI tables through the higher half direct map.
+///
+/// Tables usually live in memory that is part of the direct map already, the
+/// ones that don't (like tables in reserved memory) get mapped on demand.
+///
 #[derive(Debug, Clone, Copy)]
-struct AcpiMapper;
+pub struct AcpiMapper;
 
 impl AcpiHandler for AcpiMapper {
     unsafe fn map_physical_region<T>(
         &self,
         physical_address: usize,
         size: usize,
-    ) -> acpi::PhysicalMapping<Self, T> {
-        todo!()
+    ) -> PhysicalMapping<Self, T> {
+        let virt =
+            mmu::map_physical_region(PhysicalAddress::new(physical_address as u64), size as u64);
+
+        PhysicalMapping::new(
+            physical_address,
+            NonNull::new(virt.as_mut_ptr()).unwrap(),
+            size,
+            size,
+            *self,
+        )
     }
 
-    fn unmap_physical_region<T>(region: &acpi::PhysicalMapping<Self, T>) {
-        todo!()
+    fn unmap_physical_region<T>(_region: &PhysicalMapping<Self, T>) {
+        // The higher half direct map is permanent, mapped tables stay mapped
+        // so they can be mapped again cheaply
     }
 }
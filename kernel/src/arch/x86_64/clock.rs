@@ -0,0 +1,153 @@
+use core::{
+    arch::x86_64::{__cpuid, _rdtsc},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use spin::Once;
+
+use super::{hpet, pit};
+
+/// How long the TSC gets calibrated.
+const CALIBRATION_NS: u64 = 10_000_000;
+
+/// Where the monotonic time comes from.
+#[derive(Debug, Clone, Copy)]
+enum Source {
+    /// The TSC, ticking at the given frequency in Hz.
+    Tsc(u64),
+    /// The HPET main counter, used when the TSC stops or changes its rate.
+    Hpet,
+}
+
+static SOURCE: Once<Source> = Once::new();
+
+/// The value of the clock source when the clock was initialized.
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+/// The unix time in nanoseconds at which the monotonic clock started.
+static REALTIME_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Returns whether the TSC ticks at a constant rate in every power state.
+pub fn has_invariant_tsc() -> bool {
+    unsafe { __cpuid(0x8000_0000).eax >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0 }
+}
+
+/// Picks the clock source and calibrates the TSC.
+///
+/// The TSC is calibrated against the HPET, or against the PIT on platforms
+/// without one. A TSC that isn't invariant is only used without an HPET.
+///
+/// # Safety
+///
+/// `hpet::init` has to be called already.
+///
+pub unsafe fn init() {
+    let source = SOURCE.call_once(|| {
+        if !has_invariant_tsc() && hpet::is_available() {
+            BASE.store(hpet::counter(), Ordering::Relaxed);
+            return Source::Hpet;
+        }
+
+        let (tsc_start, frequency) = if hpet::is_available() {
+            calibrate_with_hpet()
+        } else {
+            calibrate_with_pit()
+        };
+
+        BASE.store(tsc_start, Ordering::Relaxed);
+        Source::Tsc(frequency)
+    });
+
+    match source {
+        Source::Tsc(frequency) if has_invariant_tsc() => {
+            info!("Clock source: invariant TSC at {} Hz", frequency)
+        }
+        Source::Tsc(frequency) => error!(
+            "Clock source: TSC at {} Hz, it is not invariant and there is no HPET",
+            frequency
+        ),
+        Source::Hpet => info!("Clock source: HPET, the TSC is not invariant"),
+    }
+}
+
+/// Measures the TSC frequency against the HPET.
+///
+/// Returns the TSC value the measurement started at and the frequency in Hz.
+///
+unsafe fn calibrate_with_hpet() -> (u64, u64) {
+    let hpet_start = hpet::counter();
+    let tsc_start = _rdtsc();
+
+    hpet::busy_wait_ns(CALIBRATION_NS);
+
+    let elapsed_ns = hpet::ticks_to_ns(hpet::counter() - hpet_start);
+    let elapsed_tsc = _rdtsc() - tsc_start;
+
+    let frequency = (elapsed_tsc as u128 * 1_000_000_000 / elapsed_ns as u128) as u64;
+    (tsc_start, frequency)
+}
+
+/// Measures the TSC frequency against PIT channel 2.
+///
+/// Returns the TSC value the measurement started at and the frequency in Hz.
+///
+unsafe fn calibrate_with_pit() -> (u64, u64) {
+    let calibration_ms = CALIBRATION_NS / 1_000_000;
+
+    let mut tsc_start = 0;
+    pit::wait_ms(calibration_ms, || tsc_start = _rdtsc());
+
+    let elapsed_tsc = _rdtsc() - tsc_start;
+    (tsc_start, elapsed_tsc * 1000 / calibration_ms)
+}
+
+/// Returns the nanoseconds passed since the clock was initialized.
+///
+/// The clock never goes backwards and is shared by all CPUs. Returns 0 until
+/// the clock is initialized.
+///
+pub fn monotonic_ns() -> u64 {
+    let Some(source) = SOURCE.get() else {
+        return 0;
+    };
+
+    let base = BASE.load(Ordering::Relaxed);
+
+    match *source {
+        Source::Tsc(frequency) => {
+            let ticks = unsafe { _rdtsc() }.saturating_sub(base);
+            (ticks as u128 * 1_000_000_000 / frequency as u128) as u64
+        }
+        Source::Hpet => hpet::ticks_to_ns(hpet::counter() - base),
+    }
+}
+
+/// Sets the wall clock time.
+///
+/// # Arguments
+/// * `unix_ns` - The nanoseconds passed since the unix epoch.
+///
+pub fn set_realtime_ns(unix_ns: u64) {
+    REALTIME_OFFSET.store(unix_ns.saturating_sub(monotonic_ns()), Ordering::Relaxed);
+}
+
+/// Returns the nanoseconds passed since the unix epoch.
+///
+/// Unlike the monotonic clock this jumps whenever the wall clock is set.
+///
+pub fn realtime_ns() -> u64 {
+    REALTIME_OFFSET.load(Ordering::Relaxed) + monotonic_ns()
+}
+
+/// Busy waits for at least the given number of nanoseconds.
+///
+/// # Arguments
+/// * `ns` - The time to wait in nanoseconds.
+///
+pub fn delay_ns(ns: u64) {
+    let start = monotonic_ns();
+
+    while monotonic_ns() - start < ns {
+        core::hint::spin_loop();
+    }
+}
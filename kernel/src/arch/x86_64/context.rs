@@ -0,0 +1,104 @@
+use core::arch::global_asm;
+
+/// The callee saved registers `switch_context` pushes, in the order they are popped.
+#[repr(C)]
+struct SwitchFrame {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    rbx: u64,
+    rbp: u64,
+    return_address: u64,
+}
+
+/// The saved state of a thread that isn't running.
+///
+/// Only the stack pointer is stored here, the callee saved registers live on
+/// the stack of the thread.
+///
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct Context {
+    rsp: u64,
+}
+
+impl Context {
+    /// Creates the context of a thread that hasn't run yet.
+    ///
+    /// # Arguments
+    /// * `stack_top` - The (16 byte aligned) end of the thread's stack.
+    /// * `entry` - The function the thread starts in, called with interrupts disabled.
+    ///
+    /// # Safety
+    ///
+    /// The stack must be mapped and unused.
+    ///
+    pub unsafe fn new(stack_top: u64, entry: extern "C" fn() -> !) -> Self {
+        // The entry function expects the stack to be misaligned by a return
+        // address, as if it had been called
+        let rsp = stack_top - 8 - core::mem::size_of::<SwitchFrame>() as u64;
+
+        (rsp as *mut SwitchFrame).write(SwitchFrame {
+            r15: 0,
+            r14: 0,
+            r13: 0,
+            r12: 0,
+            rbx: 0,
+            rbp: 0,
+            return_address: entry as usize as u64,
+        });
+
+        // The fake return address of the entry function
+        ((stack_top - 8) as *mut u64).write(0);
+
+        Self { rsp }
+    }
+}
+
+global_asm!(
+    r#"
+    .pushsection .text
+
+    .global switch_context
+switch_context:
+    pushq %rbp
+    pushq %rbx
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+
+    movq %rsp, (%rdi)
+    movq (%rsi), %rsp
+
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %rbx
+    popq %rbp
+    ret
+
+    .popsection
+"#,
+    options(att_syntax)
+);
+
+extern "C" {
+    fn switch_context(from: *mut Context, to: *const Context);
+}
+
+/// Saves the state of the calling thread into `from` and continues the thread saved in `to`.
+///
+/// Returns once another CPU or thread switches back to `from`.
+///
+/// # Safety
+///
+/// Interrupts have to be disabled, `to` must be a valid context that isn't
+/// running on any CPU and both pointers must stay valid until the switch
+/// back.
+///
+pub unsafe fn switch(from: *mut Context, to: *const Context) {
+    switch_context(from, to);
+}
@@ -0,0 +1,161 @@
+use core::fmt;
+
+use log::Level;
+use x86::{
+    controlregs::{cr0, cr2, cr3, cr4},
+    irq::{BREAKPOINT_VECTOR, DEBUG_VECTOR, NONMASKABLE_INTERRUPT_VECTOR, PAGE_FAULT_VECTOR},
+    segmentation::{ds, es, fs, gs},
+};
+
+use super::{
+    idt::{self, ExceptionStackFrame},
+    interrupts::InterruptContext,
+    page_fault,
+};
+
+/// Handles the CPU exceptions (vectors 0-31).
+///
+/// Debug and breakpoint exceptions as well as NMIs (like the ones sent by
+/// `ipi::send_nmi`) are reported and execution continues, page faults may be
+/// resolved by demand paging and everything else is fatal.
+///
+pub(super) fn handle(context: &mut InterruptContext) {
+    let vector = context.vector as u8;
+
+    match vector {
+        PAGE_FAULT_VECTOR => page_fault::handle(context),
+        DEBUG_VECTOR | BREAKPOINT_VECTOR => {
+            info!(
+                "{} at {:#x}",
+                ExceptionName(vector),
+                context.frame.instruction_pointer
+            );
+            log_registers(Level::Info, context);
+        }
+        NONMASKABLE_INTERRUPT_VECTOR => {
+            warn!(
+                "{} at {:#x}",
+                ExceptionName(vector),
+                context.frame.instruction_pointer
+            );
+        }
+        _ => {
+            log_registers(Level::Error, context);
+
+            panic!(
+                "Unhandled exception {} (error code: {:#x}) at {:#x}",
+                ExceptionName(vector),
+                context.error_code,
+                context.frame.instruction_pointer
+            );
+        }
+    }
+}
+
+/// Handles double faults (vector 8).
+///
+/// A double fault means the CPU failed to deliver another exception, usually
+/// because the kernel stack overflowed. There is nothing to recover here.
+///
+pub extern "x86-interrupt" fn double_fault_handler(
+    frame: ExceptionStackFrame,
+    error_code: u64,
+) -> ! {
+    panic!(
+        "Double fault (error code: {:#x}) at {:#x}, rsp: {:#x}, rflags: {:#x}",
+        error_code, frame.instruction_pointer, frame.stack_pointer, frame.cpu_flags
+    );
+}
+
+/// Prints an exception like `#GP (General-Protection Exception)`.
+///
+/// This doesn't allocate, the exception might have been raised while the heap
+/// was locked.
+///
+struct ExceptionName(u8);
+
+impl fmt::Display for ExceptionName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match idt::get_description(self.0) {
+            Some(description) => {
+                write!(f, "{} ({})", description.mnemonic, description.description)
+            }
+            None => write!(f, "vector {}", self.0),
+        }
+    }
+}
+
+/// Logs a snapshot of the registers at the time of the interrupt.
+///
+/// General purpose registers, `rip`, `rflags`, `cs` and `ss` come from the
+/// interrupted code, the control and remaining segment registers are read as
+/// they are right now.
+///
+/// # Arguments
+/// * `level` - The log level to print the registers with.
+/// * `context` - The saved state of the interrupted code.
+///
+pub(super) fn log_registers(level: Level, context: &InterruptContext) {
+    let frame = &context.frame;
+
+    log!(
+        level,
+        "RAX={:016x} RBX={:016x} RCX={:016x} RDX={:016x}",
+        context.rax,
+        context.rbx,
+        context.rcx,
+        context.rdx
+    );
+    log!(
+        level,
+        "RSI={:016x} RDI={:016x} RBP={:016x} RSP={:016x}",
+        context.rsi,
+        context.rdi,
+        context.rbp,
+        frame.stack_pointer
+    );
+    log!(
+        level,
+        "R8 ={:016x} R9 ={:016x} R10={:016x} R11={:016x}",
+        context.r8,
+        context.r9,
+        context.r10,
+        context.r11
+    );
+    log!(
+        level,
+        "R12={:016x} R13={:016x} R14={:016x} R15={:016x}",
+        context.r12,
+        context.r13,
+        context.r14,
+        context.r15
+    );
+    log!(
+        level,
+        "RIP={:016x} RFLAGS={:016x} ERR={:#x}",
+        frame.instruction_pointer,
+        frame.cpu_flags,
+        context.error_code
+    );
+    log!(
+        level,
+        "CS={:04x} SS={:04x} DS={:04x} ES={:04x} FS={:04x} GS={:04x}",
+        frame.code_segment,
+        frame.stack_segment,
+        ds().bits(),
+        es().bits(),
+        fs().bits(),
+        gs().bits()
+    );
+
+    unsafe {
+        log!(
+            level,
+            "CR0={:016x} CR2={:016x} CR3={:016x} CR4={:016x}",
+            cr0().bits(),
+            cr2(),
+            cr3(),
+            cr4().bits()
+        );
+    }
+}
@@ -1,17 +1,132 @@
+use core::mem::size_of;
+
 use x86::{
+    bits64::task::TaskStateSegment,
+    current::segmentation::Descriptor64,
     dtables::{lgdt, DescriptorTablePointer},
     segmentation::{
         load_cs, load_ds, load_es, load_fs, load_gs, load_ss, BuildDescriptor, CodeSegmentType,
-        DataSegmentType, Descriptor, DescriptorBuilder, SegmentDescriptorBuilder, SegmentSelector,
+        DataSegmentType, Descriptor, DescriptorBuilder, GateDescriptorBuilder,
+        SegmentDescriptorBuilder, SegmentSelector,
     },
+    task::load_tr,
     Ring,
 };
 
-use super::idt;
+use super::idt::InterruptGuard;
+
+pub const KERNEL_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(1, Ring::Ring0);
+pub const KERNEL_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(2, Ring::Ring0);
+
+/// The TSS descriptor is 16 bytes large and takes up two GDT slots.
+pub const TSS_SELECTOR: SegmentSelector = SegmentSelector::new(5, Ring::Ring0);
+
+/// The interrupt stack table index of the double fault stack.
+pub const DOUBLE_FAULT_IST: u8 = 1;
+
+/// The interrupt stack table index of the non maskable interrupt stack.
+pub const NMI_IST: u8 = 2;
+
+/// The interrupt stack table index of the machine check stack.
+pub const MACHINE_CHECK_IST: u8 = 3;
+
+/// The size of every IST and privilege stack.
+pub const STACK_SIZE: usize = 16 * 1024;
+
+/// The tops of the stacks a CPU switches to through its TSS.
+///
+pub struct TssStacks {
+    /// Used when an interrupt arrives while running in ring 3.
+    pub privilege: u64,
+    pub double_fault: u64,
+    pub nmi: u64,
+    pub machine_check: u64,
+}
+
+/// The global descriptor table and task state segment of a single CPU.
+///
+/// Every CPU needs its own TSS, and since loading a TSS marks its descriptor
+/// as busy, every CPU needs its own GDT as well.
+///
+#[repr(C, align(16))]
+pub struct CpuTables {
+    gdt: [Descriptor; 8],
+    tss: TaskStateSegment,
+}
+
+impl CpuTables {
+    pub const fn new() -> Self {
+        Self {
+            gdt: [Descriptor::NULL; 8],
+            tss: TaskStateSegment::new(),
+        }
+    }
+
+    /// Sets the stack the CPU switches to when an interrupt arrives in ring 3.
+    ///
+    /// # Arguments
+    /// * `stack_top` - The (16 byte aligned) end of the stack.
+    ///
+    pub fn set_kernel_stack(&mut self, stack_top: u64) {
+        self.tss.set_rsp(Ring::Ring0, stack_top);
+    }
+}
+
+/// A statically allocated stack, used by the BSP before the heap is available.
+#[repr(C, align(16))]
+struct Stack([u8; STACK_SIZE]);
+
+impl Stack {
+    const fn new() -> Self {
+        Self([0; STACK_SIZE])
+    }
 
-static mut GDT: [Descriptor; 8] = [Descriptor::NULL; 8];
+    fn top(&self) -> u64 {
+        self.0.as_ptr() as u64 + STACK_SIZE as u64
+    }
+}
+
+static mut BSP_TABLES: CpuTables = CpuTables::new();
 
+static mut BSP_PRIVILEGE_STACK: Stack = Stack::new();
+static mut BSP_DOUBLE_FAULT_STACK: Stack = Stack::new();
+static mut BSP_NMI_STACK: Stack = Stack::new();
+static mut BSP_MACHINE_CHECK_STACK: Stack = Stack::new();
+
+/// Loads the descriptor tables of the BSP.
+///
 pub unsafe fn init() {
+    let stacks = TssStacks {
+        privilege: BSP_PRIVILEGE_STACK.top(),
+        double_fault: BSP_DOUBLE_FAULT_STACK.top(),
+        nmi: BSP_NMI_STACK.top(),
+        machine_check: BSP_MACHINE_CHECK_STACK.top(),
+    };
+
+    load(&mut BSP_TABLES, &stacks);
+}
+
+/// Returns the tables of the BSP.
+///
+/// # Safety
+///
+/// Must only be called once, on the BSP.
+///
+pub unsafe fn bsp_tables() -> &'static mut CpuTables {
+    &mut BSP_TABLES
+}
+
+/// Fills in and loads the GDT and TSS of the calling CPU.
+///
+/// # Arguments
+/// * `tables` - The tables of the calling CPU, they must never be used by another CPU.
+/// * `stacks` - The stacks the TSS points to.
+///
+/// # Safety
+///
+/// The stacks must stay valid for as long as the CPU is running.
+///
+pub unsafe fn load(tables: &'static mut CpuTables, stacks: &TssStacks) {
     let code_kernel = DescriptorBuilder::code_descriptor(0, 0xFFFFF, CodeSegmentType::ExecuteRead)
         .present()
         .dpl(Ring::Ring0)
@@ -46,26 +161,46 @@ pub unsafe fn init() {
             .l()
             .finish();
 
-    GDT[1] = code_kernel;
-    GDT[2] = data_kernel;
-    GDT[3] = code_user;
-    GDT[4] = data_user;
+    let tss = &mut tables.tss;
+    tss.set_rsp(Ring::Ring0, stacks.privilege);
+    tss.set_ist(DOUBLE_FAULT_IST as usize - 1, stacks.double_fault);
+    tss.set_ist(NMI_IST as usize - 1, stacks.nmi);
+    tss.set_ist(MACHINE_CHECK_IST as usize - 1, stacks.machine_check);
 
-    idt::disable();
+    // No I/O permission bitmap, ring 3 has no port access
+    tss.iomap_base = size_of::<TaskStateSegment>() as u16;
 
-    let gdt_ptr = DescriptorTablePointer::new(&GDT);
-    lgdt(&gdt_ptr);
+    let tss_descriptor: Descriptor64 =
+        <DescriptorBuilder as GateDescriptorBuilder<u64>>::tss_descriptor(
+            tss as *const TaskStateSegment as u64,
+            size_of::<TaskStateSegment>() as u64 - 1,
+            true,
+        )
+        .present()
+        .dpl(Ring::Ring0)
+        .finish();
 
-    let code_segment = SegmentSelector::new(1, Ring::Ring0);
-    let data_segment = SegmentSelector::new(2, Ring::Ring0);
+    let gdt = &mut tables.gdt;
+    gdt[1] = code_kernel;
+    gdt[2] = data_kernel;
+    gdt[3] = code_user;
+    gdt[4] = data_user;
+
+    let tss_slot = &mut gdt[TSS_SELECTOR.index() as usize] as *mut Descriptor as *mut Descriptor64;
+    tss_slot.write_unaligned(tss_descriptor);
+
+    let _interrupts = InterruptGuard::new();
+
+    let gdt_ptr = DescriptorTablePointer::new(&tables.gdt);
+    lgdt(&gdt_ptr);
 
-    load_ss(data_segment);
-    load_ds(data_segment);
-    load_es(data_segment);
-    load_fs(data_segment);
-    load_gs(data_segment);
+    load_ss(KERNEL_DATA_SELECTOR);
+    load_ds(KERNEL_DATA_SELECTOR);
+    load_es(KERNEL_DATA_SELECTOR);
+    load_fs(KERNEL_DATA_SELECTOR);
+    load_gs(KERNEL_DATA_SELECTOR);
 
-    load_cs(code_segment);
+    load_cs(KERNEL_CODE_SELECTOR);
 
-    idt::enable();
+    load_tr(TSS_SELECTOR);
 }
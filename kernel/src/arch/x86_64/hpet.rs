@@ -0,0 +1,153 @@
+use core::{
+    ptr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use ::acpi::HpetInfo;
+use spin::Once;
+
+use super::{acpi, mmu};
+use crate::arch::{PhysicalAddress, VirtualAddress};
+
+const REG_CAPABILITIES: u64 = 0x000;
+const REG_CONFIGURATION: u64 = 0x010;
+const REG_MAIN_COUNTER: u64 = 0x0F0;
+
+const CONFIGURATION_ENABLE: u64 = 1 << 0;
+const CONFIGURATION_LEGACY_ROUTING: u64 = 1 << 1;
+
+const FEMTOSECONDS_PER_NANOSECOND: u64 = 1_000_000;
+
+struct Hpet {
+    base: VirtualAddress,
+
+    /// The length of a counter tick in femtoseconds.
+    period: u64,
+    counter_is_64bits: bool,
+}
+
+impl Hpet {
+    unsafe fn read(&self, reg: u64) -> u64 {
+        ptr::read_volatile((self.base.as_u64() + reg) as *const u64)
+    }
+
+    unsafe fn write(&self, reg: u64, value: u64) {
+        ptr::write_volatile((self.base.as_u64() + reg) as *mut u64, value)
+    }
+}
+
+static HPET: Once<Hpet> = Once::new();
+
+/// The last counter value handed out, extended to 64 bits for 32 bit counters.
+static LAST_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Enables the HPET described by the ACPI tables and lets its main counter run.
+///
+/// Returns `false` if the platform has no HPET.
+///
+/// # Safety
+///
+/// The ACPI tables and the kernel page tables have to be set up already.
+///
+pub unsafe fn init() -> bool {
+    let Ok(info) = acpi::with_tables(HpetInfo::new) else {
+        info!("HPET: not present");
+        return false;
+    };
+
+    let hpet = HPET.call_once(|| {
+        let base = mmu::map_mmio(PhysicalAddress::new(info.base_address as u64), 0x400);
+
+        let mut hpet = Hpet {
+            base,
+            period: 0,
+            counter_is_64bits: info.main_counter_is_64bits(),
+        };
+        hpet.period = hpet.read(REG_CAPABILITIES) >> 32;
+
+        // Interrupts are routed through the I/O APIC, never through the legacy PIC lines
+        let configuration = hpet.read(REG_CONFIGURATION) & !CONFIGURATION_LEGACY_ROUTING;
+        hpet.write(REG_CONFIGURATION, configuration & !CONFIGURATION_ENABLE);
+        hpet.write(REG_MAIN_COUNTER, 0);
+        hpet.write(REG_CONFIGURATION, configuration | CONFIGURATION_ENABLE);
+
+        hpet
+    });
+
+    info!(
+        "HPET at {:#x} running at {} Hz ({} comparator(s), 64 bit counter: {})",
+        info.base_address,
+        frequency(),
+        info.num_comparators(),
+        hpet.counter_is_64bits
+    );
+
+    true
+}
+
+fn hpet() -> &'static Hpet {
+    HPET.get().expect("HPET is not initialized yet")
+}
+
+/// Returns whether the HPET was found and enabled.
+pub fn is_available() -> bool {
+    HPET.get().is_some()
+}
+
+/// Returns the frequency of the main counter in Hz.
+pub fn frequency() -> u64 {
+    1_000_000_000_000_000 / hpet().period
+}
+
+/// Returns the value of the main counter.
+///
+/// 32 bit counters are extended to 64 bits, as long as the counter is read
+/// at least once per wrap around (every few minutes).
+///
+pub fn counter() -> u64 {
+    let hpet = hpet();
+    let value = unsafe { hpet.read(REG_MAIN_COUNTER) };
+
+    if hpet.counter_is_64bits {
+        return value;
+    }
+
+    let low = value & 0xFFFF_FFFF;
+    let mut last = LAST_COUNTER.load(Ordering::Relaxed);
+
+    loop {
+        let mut extended = (last & !0xFFFF_FFFF) | low;
+        if extended < last {
+            extended += 1 << 32;
+        }
+
+        match LAST_COUNTER.compare_exchange_weak(
+            last,
+            extended,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => return extended,
+            Err(current) if current >= extended => return current,
+            Err(current) => last = current,
+        }
+    }
+}
+
+/// Converts a number of counter ticks into nanoseconds.
+pub fn ticks_to_ns(ticks: u64) -> u64 {
+    (ticks as u128 * hpet().period as u128 / FEMTOSECONDS_PER_NANOSECOND as u128) as u64
+}
+
+/// Busy waits for at least the given number of nanoseconds.
+///
+/// # Arguments
+/// * `ns` - The time to wait in nanoseconds.
+///
+pub fn busy_wait_ns(ns: u64) {
+    let start = counter();
+
+    while ticks_to_ns(counter() - start) < ns {
+        core::hint::spin_loop();
+    }
+}
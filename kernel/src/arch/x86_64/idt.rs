@@ -1,15 +1,26 @@
 use x86::{
+    bits64::rflags::{self, RFlags},
     current::segmentation::Descriptor64,
     dtables::{lidt, DescriptorTablePointer},
     irq::{
-        self, InterruptDescription, ALIGNMENT_CHECK_VECTOR, DOUBLE_FAULT_VECTOR, EXCEPTIONS,
-        GENERAL_PROTECTION_FAULT_VECTOR, INVALID_TSS_VECTOR, PAGE_FAULT_VECTOR,
-        SEGMENT_NOT_PRESENT_VECTOR, STACK_SEGEMENT_FAULT_VECTOR,
+        self, InterruptDescription, ALIGNMENT_CHECK_VECTOR, BOUND_RANGE_EXCEEDED_VECTOR,
+        BREAKPOINT_VECTOR, COPROCESSOR_SEGMENT_OVERRUN_VECTOR, DEBUG_VECTOR,
+        DEVICE_NOT_AVAILABLE_VECTOR, DIVIDE_ERROR_VECTOR, DOUBLE_FAULT_VECTOR, EXCEPTIONS,
+        GENERAL_PROTECTION_FAULT_VECTOR, INVALID_OPCODE_VECTOR, INVALID_TSS_VECTOR,
+        MACHINE_CHECK_VECTOR, NONMASKABLE_INTERRUPT_VECTOR, OVERFLOW_VECTOR, PAGE_FAULT_VECTOR,
+        SEGMENT_NOT_PRESENT_VECTOR, SIMD_FLOATING_POINT_VECTOR, STACK_SEGEMENT_FAULT_VECTOR,
+        VIRTUALIZATION_VECTOR, X87_FPU_VECTOR,
     },
     segmentation::{BuildDescriptor, DescriptorBuilder, GateDescriptorBuilder, SegmentSelector},
     Ring,
 };
 
+use super::{
+    exceptions,
+    gdt::{DOUBLE_FAULT_IST, KERNEL_CODE_SELECTOR, MACHINE_CHECK_IST, NMI_IST},
+    interrupts,
+};
+
 #[repr(transparent)]
 pub struct InterruptDescriptorTable([Descriptor64; 256]);
 sa::const_assert_eq!(core::mem::size_of::<InterruptDescriptorTable>(), 4096);
@@ -40,158 +51,68 @@ impl InterruptDescriptorTable {
         Some(&EXCEPTIONS[index])
     }
 
-    /// Sets the interrupt handler for the given index.
+    /// Installs the given handler as an interrupt gate.
+    ///
+    /// Interrupts are disabled while the handler runs.
     ///
     /// # Arguments
-    /// * `index` - The index of the interrupt to set the handler for.
+    /// * `handler` - The interrupt handler, the variant decides the vector.
     /// * `segment` - The code segment to use for the interrupt handler.
     /// * `ist` - The interrupt stack table index to use for the interrupt handler.
     /// * `dpl` - The privilege level to use for the interrupt handler.
-    /// * `handler` - The interrupt handler function.
     ///
     pub fn set_interrupt(
         &mut self,
-        index: u8,
+        handler: Handler,
         segment: SegmentSelector,
         ist: u8,
         dpl: Ring,
-        handler: unsafe extern "x86-interrupt" fn(stack_frame: ExceptionStackFrame),
     ) {
-        // Some interrupts can contain error codes, we cannot use this function for them
-        assert!(
-            index != DOUBLE_FAULT_VECTOR
-                && index != INVALID_TSS_VECTOR
-                && index != SEGMENT_NOT_PRESENT_VECTOR
-                && index != STACK_SEGEMENT_FAULT_VECTOR
-                && index != GENERAL_PROTECTION_FAULT_VECTOR
-                && index != PAGE_FAULT_VECTOR
-                && index != ALIGNMENT_CHECK_VECTOR
-        );
-
-        // Some interrupts are reserved and cannot be used
-        assert!(index != 15 && !(index >= 21 && index <= 31));
-
-        self.0[index as usize] = DescriptorBuilder::interrupt_descriptor(segment, handler as u64)
-            .present()
-            .ist(ist)
-            .dpl(dpl)
-            .finish();
+        self.0[handler.vector() as usize] =
+            DescriptorBuilder::interrupt_descriptor(segment, handler.address())
+                .present()
+                .ist(ist)
+                .dpl(dpl)
+                .finish();
     }
 
-    /// Sets the interrupt handler for the given index but have a error code
+    /// Installs the given handler as a trap gate.
     ///
-    /// # Arguments
-    /// * `index` - The index of the interrupt to set the handler for.
-    /// * `segment` - The code segment to use for the interrupt handler.
-    /// * `ist` - The interrupt stack table index to use for the interrupt handler.
-    /// * `dpl` - The privilege level to use for the interrupt handler.
-    /// * `handler` - The interrupt handler function.
-    ///
-    pub fn set_interrupt_with_error_code(
-        &mut self,
-        index: u8,
-        segment: SegmentSelector,
-        ist: u8,
-        dpl: Ring,
-        handler: unsafe extern "x86-interrupt" fn(
-            stack_frame: ExceptionStackFrame,
-            error_code: u32,
-        ),
-    ) {
-        // We have to make sure that the interrupt we are setting up is one that has an error code
-        assert!(
-            index == DOUBLE_FAULT_VECTOR
-                && index == INVALID_TSS_VECTOR
-                && index == SEGMENT_NOT_PRESENT_VECTOR
-                && index == STACK_SEGEMENT_FAULT_VECTOR
-                && index == GENERAL_PROTECTION_FAULT_VECTOR
-                && index == PAGE_FAULT_VECTOR
-                && index == ALIGNMENT_CHECK_VECTOR
-                && (index >= 31)
-        );
-
-        self.0[index as usize] = DescriptorBuilder::interrupt_descriptor(segment, handler as u64)
-            .present()
-            .ist(ist)
-            .dpl(dpl)
-            .finish();
-    }
-
-    /// Sets the trap handler for the given index.
+    /// Unlike interrupt gates, trap gates leave interrupts enabled.
     ///
     /// # Arguments
-    /// * `index` - The index of the trap to set the handler for.
+    /// * `handler` - The trap handler, the variant decides the vector.
     /// * `segment` - The code segment to use for the trap handler.
     /// * `ist` - The interrupt stack table index to use for the trap handler.
     /// * `dpl` - The privilege level to use for the trap handler.
-    /// * `handler` - The trap handler function.
     ///
-    pub fn set_trap(
-        &mut self,
-        index: u8,
-        segment: SegmentSelector,
-        ist: u8,
-        dpl: Ring,
-        handler: unsafe extern "x86-interrupt" fn(stack_frame: ExceptionStackFrame),
-    ) {
-        // Some interrupts can contain error codes, we cannot use this function for them
-        assert!(
-            index != DOUBLE_FAULT_VECTOR
-                && index != INVALID_TSS_VECTOR
-                && index != SEGMENT_NOT_PRESENT_VECTOR
-                && index != STACK_SEGEMENT_FAULT_VECTOR
-                && index != GENERAL_PROTECTION_FAULT_VECTOR
-                && index != PAGE_FAULT_VECTOR
-                && index != ALIGNMENT_CHECK_VECTOR
-        );
-
-        // Some interrupts are reserved and cannot be used
-        assert!(index != 15 && !(index >= 21 && index <= 31));
-
-        self.0[index as usize] = DescriptorBuilder::trap_gate_descriptor(segment, handler as u64)
-            .present()
-            .ist(ist)
-            .dpl(dpl)
-            .finish();
+    pub fn set_trap(&mut self, handler: Handler, segment: SegmentSelector, ist: u8, dpl: Ring) {
+        self.0[handler.vector() as usize] =
+            DescriptorBuilder::trap_gate_descriptor(segment, handler.address())
+                .present()
+                .ist(ist)
+                .dpl(dpl)
+                .finish();
     }
 
-    /// Sets the trap handler for the given index but have a error code
+    /// Points the given index at one of the assembly entry stubs.
+    ///
+    /// The stubs save the full register state and dispatch to Rust on their own,
+    /// so this works for every vector, with or without an error code.
     ///
     /// # Arguments
-    /// * `index` - The index of the trap to set the handler for.
-    /// * `segment` - The code segment to use for the trap handler.
-    /// * `ist` - The interrupt stack table index to use for the trap handler.
-    /// * `dpl` - The privilege level to use for the trap handler.
-    /// * `handler` - The trap handler function.
+    /// * `index` - The index of the interrupt to set the stub for.
+    /// * `segment` - The code segment to use for the interrupt handler.
+    /// * `ist` - The interrupt stack table index to use for the interrupt handler.
+    /// * `dpl` - The privilege level to use for the interrupt handler.
     ///
-    pub fn set_trap_with_error_code(
-        &mut self,
-        index: u8,
-        segment: SegmentSelector,
-        ist: u8,
-        dpl: Ring,
-        handler: unsafe extern "x86-interrupt" fn(
-            stack_frame: ExceptionStackFrame,
-            error_code: u32,
-        ),
-    ) {
-        // We have to make sure that the interrupt we are setting up is one that has an error code
-        assert!(
-            index == DOUBLE_FAULT_VECTOR
-                && index == INVALID_TSS_VECTOR
-                && index == SEGMENT_NOT_PRESENT_VECTOR
-                && index == STACK_SEGEMENT_FAULT_VECTOR
-                && index == GENERAL_PROTECTION_FAULT_VECTOR
-                && index == PAGE_FAULT_VECTOR
-                && index == ALIGNMENT_CHECK_VECTOR
-                && (index >= 31)
-        );
-
-        self.0[index as usize] = DescriptorBuilder::trap_gate_descriptor(segment, handler as u64)
-            .present()
-            .ist(ist)
-            .dpl(dpl)
-            .finish();
+    pub fn set_stub(&mut self, index: u8, segment: SegmentSelector, ist: u8, dpl: Ring) {
+        self.0[index as usize] =
+            DescriptorBuilder::interrupt_descriptor(segment, interrupts::stub_address(index))
+                .present()
+                .ist(ist)
+                .dpl(dpl)
+                .finish();
     }
 
     /// Disables the interrupt handler for the given index.
@@ -200,10 +121,135 @@ impl InterruptDescriptorTable {
     /// * `index` - The index of the interrupt to disable.
     ///
     pub fn unset(&mut self, index: u8) {
-        self.0[index as usize].set_ist(index)
+        // A null descriptor has the present bit cleared
+        self.0[index as usize] = Descriptor64::NULL;
+    }
+}
+
+/// A handler for vectors without an error code.
+pub type HandlerFunc = extern "x86-interrupt" fn(stack_frame: ExceptionStackFrame);
+
+/// A handler for vectors with an error code.
+pub type HandlerFuncWithErrorCode =
+    extern "x86-interrupt" fn(stack_frame: ExceptionStackFrame, error_code: u64);
+
+/// A handler for aborts without an error code, execution can't continue after them.
+pub type DivergingHandlerFunc = extern "x86-interrupt" fn(stack_frame: ExceptionStackFrame) -> !;
+
+/// A handler for aborts with an error code, execution can't continue after them.
+pub type DivergingHandlerFuncWithErrorCode =
+    extern "x86-interrupt" fn(stack_frame: ExceptionStackFrame, error_code: u64) -> !;
+
+/// An interrupt handler together with the vector it handles.
+///
+/// Every exception has its own variant with the handler signature matching
+/// what the CPU pushes for it, so a handler can't be installed for a vector
+/// that would corrupt its stack. Reserved exceptions have no variant at all.
+///
+#[derive(Clone, Copy)]
+pub enum Handler {
+    DivideError(HandlerFunc),
+    Debug(HandlerFunc),
+    NonMaskableInterrupt(HandlerFunc),
+    Breakpoint(HandlerFunc),
+    Overflow(HandlerFunc),
+    BoundRangeExceeded(HandlerFunc),
+    InvalidOpcode(HandlerFunc),
+    DeviceNotAvailable(HandlerFunc),
+    DoubleFault(DivergingHandlerFuncWithErrorCode),
+    CoprocessorSegmentOverrun(HandlerFunc),
+    InvalidTss(HandlerFuncWithErrorCode),
+    SegmentNotPresent(HandlerFuncWithErrorCode),
+    StackSegmentFault(HandlerFuncWithErrorCode),
+    GeneralProtectionFault(HandlerFuncWithErrorCode),
+    PageFault(HandlerFuncWithErrorCode),
+    X87FloatingPoint(HandlerFunc),
+    AlignmentCheck(HandlerFuncWithErrorCode),
+    MachineCheck(DivergingHandlerFunc),
+    SimdFloatingPoint(HandlerFunc),
+    Virtualization(HandlerFunc),
+    ControlProtection(HandlerFuncWithErrorCode),
+    HypervisorInjection(HandlerFunc),
+    VmmCommunication(HandlerFuncWithErrorCode),
+    Security(HandlerFuncWithErrorCode),
+
+    /// A regular interrupt, the vector has to be in the range of 32-255.
+    Interrupt(u8, HandlerFunc),
+}
+
+impl Handler {
+    /// Returns the vector this handler is installed at.
+    ///
+    pub fn vector(&self) -> u8 {
+        match *self {
+            Self::DivideError(_) => DIVIDE_ERROR_VECTOR,
+            Self::Debug(_) => DEBUG_VECTOR,
+            Self::NonMaskableInterrupt(_) => NONMASKABLE_INTERRUPT_VECTOR,
+            Self::Breakpoint(_) => BREAKPOINT_VECTOR,
+            Self::Overflow(_) => OVERFLOW_VECTOR,
+            Self::BoundRangeExceeded(_) => BOUND_RANGE_EXCEEDED_VECTOR,
+            Self::InvalidOpcode(_) => INVALID_OPCODE_VECTOR,
+            Self::DeviceNotAvailable(_) => DEVICE_NOT_AVAILABLE_VECTOR,
+            Self::DoubleFault(_) => DOUBLE_FAULT_VECTOR,
+            Self::CoprocessorSegmentOverrun(_) => COPROCESSOR_SEGMENT_OVERRUN_VECTOR,
+            Self::InvalidTss(_) => INVALID_TSS_VECTOR,
+            Self::SegmentNotPresent(_) => SEGMENT_NOT_PRESENT_VECTOR,
+            Self::StackSegmentFault(_) => STACK_SEGEMENT_FAULT_VECTOR,
+            Self::GeneralProtectionFault(_) => GENERAL_PROTECTION_FAULT_VECTOR,
+            Self::PageFault(_) => PAGE_FAULT_VECTOR,
+            Self::X87FloatingPoint(_) => X87_FPU_VECTOR,
+            Self::AlignmentCheck(_) => ALIGNMENT_CHECK_VECTOR,
+            Self::MachineCheck(_) => MACHINE_CHECK_VECTOR,
+            Self::SimdFloatingPoint(_) => SIMD_FLOATING_POINT_VECTOR,
+            Self::Virtualization(_) => VIRTUALIZATION_VECTOR,
+            Self::ControlProtection(_) => CONTROL_PROTECTION_VECTOR,
+            Self::HypervisorInjection(_) => HYPERVISOR_INJECTION_VECTOR,
+            Self::VmmCommunication(_) => VMM_COMMUNICATION_VECTOR,
+            Self::Security(_) => SECURITY_VECTOR,
+            Self::Interrupt(vector, _) => {
+                assert!(vector >= 32, "Vector {} is reserved for exceptions", vector);
+                vector
+            }
+        }
+    }
+
+    fn address(&self) -> u64 {
+        match *self {
+            Self::DoubleFault(handler) => handler as usize as u64,
+            Self::MachineCheck(handler) => handler as usize as u64,
+            Self::InvalidTss(handler)
+            | Self::SegmentNotPresent(handler)
+            | Self::StackSegmentFault(handler)
+            | Self::GeneralProtectionFault(handler)
+            | Self::PageFault(handler)
+            | Self::AlignmentCheck(handler)
+            | Self::ControlProtection(handler)
+            | Self::VmmCommunication(handler)
+            | Self::Security(handler) => handler as usize as u64,
+            Self::DivideError(handler)
+            | Self::Debug(handler)
+            | Self::NonMaskableInterrupt(handler)
+            | Self::Breakpoint(handler)
+            | Self::Overflow(handler)
+            | Self::BoundRangeExceeded(handler)
+            | Self::InvalidOpcode(handler)
+            | Self::DeviceNotAvailable(handler)
+            | Self::CoprocessorSegmentOverrun(handler)
+            | Self::X87FloatingPoint(handler)
+            | Self::SimdFloatingPoint(handler)
+            | Self::Virtualization(handler)
+            | Self::HypervisorInjection(handler)
+            | Self::Interrupt(_, handler) => handler as usize as u64,
+        }
     }
 }
 
+// The x86 crate only names the exceptions up to 20
+const CONTROL_PROTECTION_VECTOR: u8 = 21;
+const HYPERVISOR_INJECTION_VECTOR: u8 = 28;
+const VMM_COMMUNICATION_VECTOR: u8 = 29;
+const SECURITY_VECTOR: u8 = 30;
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
 pub struct ExceptionStackFrame {
@@ -216,12 +262,60 @@ pub struct ExceptionStackFrame {
 
 static mut IDT: InterruptDescriptorTable = InterruptDescriptorTable::new();
 
+/// Returns the description of the given exception, see `InterruptDescriptorTable::get_description`.
+///
+pub fn get_description(vector: u8) -> Option<&'static InterruptDescription> {
+    unsafe { IDT.get_description(vector as usize) }
+}
+
+/// Installs the given handler as an interrupt gate into the IDT shared by all CPUs.
+///
+/// # Safety
+///
+/// The handler must be ready to be called as soon as this returns.
+///
+pub unsafe fn set_handler(handler: Handler) {
+    IDT.set_interrupt(handler, KERNEL_CODE_SELECTOR, 0, Ring::Ring0);
+}
+
 pub unsafe fn init() {
-    disable();
+    let _interrupts = InterruptGuard::new();
+
+    // Every vector goes through the common entry stubs, even the reserved
+    // exceptions, so a misbehaving CPU or hypervisor gets reported instead of
+    // turning into a triple fault
+    for vector in 0..=u8::MAX {
+        // NMIs and machine checks can arrive at any point, even while the
+        // stack is in an unusable state, so they get dedicated stacks
+        let ist = match vector {
+            NONMASKABLE_INTERRUPT_VECTOR => NMI_IST,
+            MACHINE_CHECK_VECTOR => MACHINE_CHECK_IST,
+            _ => 0,
+        };
+
+        IDT.set_stub(vector, KERNEL_CODE_SELECTOR, ist, Ring::Ring0);
+    }
+
+    // The state is too broken after a double fault to go through the stubs,
+    // it most likely was caused by a kernel stack overflow
+    IDT.set_interrupt(
+        Handler::DoubleFault(exceptions::double_fault_handler),
+        KERNEL_CODE_SELECTOR,
+        DOUBLE_FAULT_IST,
+        Ring::Ring0,
+    );
 
     IDT.load();
+}
 
-    enable();
+/// Loads the IDT shared by all CPUs on the calling CPU.
+///
+/// # Safety
+///
+/// `init` must have been called on the BSP before.
+///
+pub unsafe fn load() {
+    IDT.load();
 }
 
 pub fn enable() {
@@ -235,3 +329,36 @@ pub fn disable() {
         irq::disable();
     }
 }
+
+/// Returns whether interrupts are enabled on the calling CPU.
+pub fn is_enabled() -> bool {
+    rflags::read().contains(RFlags::FLAGS_IF)
+}
+
+/// Disables interrupts on the calling CPU until dropped.
+///
+/// Dropping the guard restores the state from before it was created, so
+/// guards nest and only the outermost one turns interrupts back on.
+///
+#[must_use]
+pub struct InterruptGuard {
+    enabled: bool,
+}
+
+impl InterruptGuard {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        let enabled = is_enabled();
+        disable();
+
+        Self { enabled }
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        if self.enabled {
+            enable();
+        }
+    }
+}
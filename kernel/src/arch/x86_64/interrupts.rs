@@ -0,0 +1,150 @@
+use core::arch::global_asm;
+
+use super::{exceptions, idt::ExceptionStackFrame, irq};
+use crate::task::scheduler;
+
+/// The number of vectors that have an entry stub.
+pub const STUB_COUNT: usize = 256;
+
+/// The size of a single entry stub, every stub is padded to this size so the
+/// stub of a vector can be found without a lookup table.
+const STUB_SIZE: usize = 16;
+
+/// The state of the interrupted code, as saved by the entry stubs.
+///
+/// Handlers may modify the context, the interrupted code resumes with the
+/// modified state once the handler returns.
+///
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct InterruptContext {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+
+    /// The vector that was raised.
+    pub vector: u64,
+    /// The error code pushed by the CPU, zero for vectors without one.
+    pub error_code: u64,
+
+    pub frame: ExceptionStackFrame,
+}
+
+// Every stub pushes a dummy error code if the CPU doesn't push one, so the
+// context has the same layout for every vector. The CPU aligns the stack to
+// 16 bytes before pushing its frame and the stubs push an even number of
+// words, so the stack is properly aligned when the dispatcher gets called.
+global_asm!(
+    r#"
+    .pushsection .text
+
+    .p2align 4
+    .global interrupt_stubs
+interrupt_stubs:
+    .set vector, 0
+    .rept {stub_count}
+    .p2align 4
+    .if !(vector == 8 || (vector >= 10 && vector <= 14) || vector == 17 || vector == 21 || vector == 29 || vector == 30)
+    pushq $0
+    .endif
+    pushq $vector
+    jmp interrupt_common
+    .set vector, vector + 1
+    .endr
+
+interrupt_common:
+    // Switch to the kernel GS base if we came from ring 3, the saved CS
+    // follows the vector, the error code and the saved RIP
+    testb $3, 24(%rsp)
+    jz 1f
+    swapgs
+1:
+    pushq %rax
+    pushq %rbx
+    pushq %rcx
+    pushq %rdx
+    pushq %rsi
+    pushq %rdi
+    pushq %rbp
+    pushq %r8
+    pushq %r9
+    pushq %r10
+    pushq %r11
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+
+    movq %rsp, %rdi
+    cld
+    call {dispatch}
+
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %r11
+    popq %r10
+    popq %r9
+    popq %r8
+    popq %rbp
+    popq %rdi
+    popq %rsi
+    popq %rdx
+    popq %rcx
+    popq %rbx
+    popq %rax
+
+    testb $3, 24(%rsp)
+    jz 1f
+    swapgs
+1:
+    // Drop the vector and the error code
+    addq $16, %rsp
+    iretq
+
+    .popsection
+"#,
+    stub_count = const STUB_COUNT,
+    dispatch = sym interrupt_dispatch,
+    options(att_syntax)
+);
+
+extern "C" {
+    fn interrupt_stubs();
+}
+
+/// Returns the address of the entry stub for the given vector.
+///
+pub fn stub_address(vector: u8) -> u64 {
+    assert!(
+        (vector as usize) < STUB_COUNT,
+        "Vector {} has no entry stub",
+        vector
+    );
+
+    interrupt_stubs as usize as u64 + (vector as usize * STUB_SIZE) as u64
+}
+
+extern "C" fn interrupt_dispatch(context: &mut InterruptContext) {
+    if context.vector < irq::FIRST_VECTOR as u64 {
+        exceptions::handle(context);
+    } else {
+        irq::dispatch(context);
+
+        // The interrupt is acknowledged, the thread may give up the CPU now
+        scheduler::preempt();
+    }
+}
@@ -0,0 +1,293 @@
+use core::ptr;
+
+use ::acpi::{platform::interrupt, InterruptModel};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::{acpi, mmu};
+use crate::arch::{PhysicalAddress, VirtualAddress};
+
+const REG_SELECT: u64 = 0x00;
+const REG_WINDOW: u64 = 0x10;
+
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION_TABLE: u32 = 0x10;
+
+const ENTRY_ACTIVE_LOW: u64 = 1 << 13;
+const ENTRY_LEVEL_TRIGGERED: u64 = 1 << 15;
+const ENTRY_MASKED: u64 = 1 << 16;
+const ENTRY_DESTINATION_SHIFT: u64 = 56;
+
+/// The number of ISA interrupts, these are the only ones that can be overridden.
+pub const ISA_IRQ_COUNT: usize = 16;
+
+/// The polarity of an interrupt line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// When an interrupt line is considered to be raised.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+/// Where an ISA interrupt ends up at the I/O APIC.
+#[derive(Debug, Clone, Copy)]
+pub struct IsaRoute {
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger_mode: TriggerMode,
+
+    /// Whether the MADT contains an interrupt source override for this interrupt.
+    pub overridden: bool,
+}
+
+/// A single I/O APIC.
+struct IoApic {
+    id: u8,
+    base: VirtualAddress,
+
+    /// The first global system interrupt handled by this I/O APIC.
+    gsi_base: u32,
+    /// The number of interrupt inputs of this I/O APIC.
+    inputs: u32,
+}
+
+impl IoApic {
+    unsafe fn read(&self, reg: u32) -> u32 {
+        ptr::write_volatile((self.base.as_u64() + REG_SELECT) as *mut u32, reg);
+        ptr::read_volatile((self.base.as_u64() + REG_WINDOW) as *const u32)
+    }
+
+    unsafe fn write(&self, reg: u32, value: u32) {
+        ptr::write_volatile((self.base.as_u64() + REG_SELECT) as *mut u32, reg);
+        ptr::write_volatile((self.base.as_u64() + REG_WINDOW) as *mut u32, value);
+    }
+
+    unsafe fn read_entry(&self, input: u32) -> u64 {
+        let reg = REG_REDIRECTION_TABLE + input * 2;
+        self.read(reg) as u64 | (self.read(reg + 1) as u64) << 32
+    }
+
+    unsafe fn write_entry(&self, input: u32, entry: u64) {
+        let reg = REG_REDIRECTION_TABLE + input * 2;
+
+        // Mask the entry while it is half written
+        self.write(reg, ENTRY_MASKED as u32);
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.inputs).contains(&gsi)
+    }
+}
+
+struct IoApics {
+    io_apics: Vec<IoApic>,
+    isa_routes: [IsaRoute; ISA_IRQ_COUNT],
+}
+
+impl IoApics {
+    fn find(&self, gsi: u32) -> &IoApic {
+        self.io_apics
+            .iter()
+            .find(|io_apic| io_apic.handles(gsi))
+            .unwrap_or_else(|| panic!("No I/O APIC handles GSI {}", gsi))
+    }
+}
+
+static IO_APICS: Mutex<Option<IoApics>> = Mutex::new(None);
+
+/// Finds every I/O APIC in the MADT and masks all of their inputs.
+///
+/// # Safety
+///
+/// The ACPI tables and the kernel page tables have to be set up already.
+///
+pub unsafe fn init() {
+    let InterruptModel::Apic(apic) = acpi::platform_info().interrupt_model else {
+        panic!("The platform has no I/O APIC");
+    };
+
+    let io_apics = apic
+        .io_apics
+        .iter()
+        .map(|io_apic| {
+            let base = mmu::map_mmio(PhysicalAddress::new(io_apic.address as u64), 0x20);
+
+            let mut io_apic = IoApic {
+                id: io_apic.id,
+                base,
+                gsi_base: io_apic.global_system_interrupt_base,
+                inputs: 0,
+            };
+            io_apic.inputs = ((io_apic.read(REG_VERSION) >> 16) & 0xFF) + 1;
+
+            for input in 0..io_apic.inputs {
+                io_apic.write_entry(input, ENTRY_MASKED);
+            }
+
+            info!(
+                "I/O APIC {} at {:#x} handles GSI {} - {}",
+                io_apic.id,
+                base.as_u64(),
+                io_apic.gsi_base,
+                io_apic.gsi_base + io_apic.inputs - 1
+            );
+
+            io_apic
+        })
+        .collect();
+
+    // ISA interrupts are identity mapped, edge triggered and active high unless
+    // the firmware tells us otherwise
+    let mut isa_routes = [IsaRoute {
+        gsi: 0,
+        polarity: Polarity::ActiveHigh,
+        trigger_mode: TriggerMode::Edge,
+        overridden: false,
+    }; ISA_IRQ_COUNT];
+
+    for (irq, route) in isa_routes.iter_mut().enumerate() {
+        route.gsi = irq as u32;
+    }
+
+    for source_override in &apic.interrupt_source_overrides {
+        let Some(route) = isa_routes.get_mut(source_override.isa_source as usize) else {
+            continue;
+        };
+
+        route.gsi = source_override.global_system_interrupt;
+        route.overridden = true;
+
+        route.polarity = match source_override.polarity {
+            interrupt::Polarity::ActiveLow => Polarity::ActiveLow,
+            _ => Polarity::ActiveHigh,
+        };
+
+        route.trigger_mode = match source_override.trigger_mode {
+            interrupt::TriggerMode::Level => TriggerMode::Level,
+            _ => TriggerMode::Edge,
+        };
+
+        trace!(
+            "ISA IRQ {} is routed to GSI {} ({:?}, {:?})",
+            source_override.isa_source,
+            route.gsi,
+            route.polarity,
+            route.trigger_mode
+        );
+    }
+
+    *IO_APICS.lock() = Some(IoApics {
+        io_apics,
+        isa_routes,
+    });
+}
+
+fn with_io_apics<R>(f: impl FnOnce(&IoApics) -> R) -> R {
+    f(IO_APICS
+        .lock()
+        .as_ref()
+        .expect("I/O APICs are not initialized yet"))
+}
+
+/// Returns where the given ISA interrupt arrives at, honoring the interrupt
+/// source overrides of the MADT.
+///
+pub fn isa_route(irq: u8) -> IsaRoute {
+    with_io_apics(|io_apics| io_apics.isa_routes[irq as usize])
+}
+
+/// Routes a global system interrupt to the given vector of a CPU and unmasks it.
+///
+/// # Arguments
+/// * `gsi` - The global system interrupt to route.
+/// * `vector` - The vector the interrupt is delivered at.
+/// * `apic_id` - The local APIC id of the CPU receiving the interrupt.
+/// * `polarity` - The polarity of the interrupt line.
+/// * `trigger_mode` - The trigger mode of the interrupt line.
+///
+pub fn route(gsi: u32, vector: u8, apic_id: u32, polarity: Polarity, trigger_mode: TriggerMode) {
+    assert!(
+        vector >= 32,
+        "Cannot route GSI {} to exception vector {}",
+        gsi,
+        vector
+    );
+    assert!(
+        apic_id <= 0xFF,
+        "APIC id {} is not addressable by the I/O APIC",
+        apic_id
+    );
+
+    let mut entry = vector as u64 | (apic_id as u64) << ENTRY_DESTINATION_SHIFT;
+
+    if polarity == Polarity::ActiveLow {
+        entry |= ENTRY_ACTIVE_LOW;
+    }
+
+    if trigger_mode == TriggerMode::Level {
+        entry |= ENTRY_LEVEL_TRIGGERED;
+    }
+
+    with_io_apics(|io_apics| {
+        let io_apic = io_apics.find(gsi);
+        unsafe { io_apic.write_entry(gsi - io_apic.gsi_base, entry) };
+    });
+
+    trace!(
+        "Routed GSI {} to vector {:#x} of APIC {}",
+        gsi,
+        vector,
+        apic_id
+    );
+}
+
+/// Routes an ISA interrupt to the given vector of a CPU and unmasks it.
+///
+/// # Arguments
+/// * `irq` - The ISA interrupt to route.
+/// * `vector` - The vector the interrupt is delivered at.
+/// * `apic_id` - The local APIC id of the CPU receiving the interrupt.
+///
+pub fn route_isa(irq: u8, vector: u8, apic_id: u32) {
+    let isa_route = isa_route(irq);
+
+    route(
+        isa_route.gsi,
+        vector,
+        apic_id,
+        isa_route.polarity,
+        isa_route.trigger_mode,
+    );
+}
+
+/// Masks or unmasks a global system interrupt without changing its route.
+///
+/// # Arguments
+/// * `gsi` - The global system interrupt to (un)mask.
+/// * `masked` - Whether the interrupt should be masked.
+///
+pub fn set_masked(gsi: u32, masked: bool) {
+    with_io_apics(|io_apics| {
+        let io_apic = io_apics.find(gsi);
+        let input = gsi - io_apic.gsi_base;
+
+        unsafe {
+            let entry = io_apic.read_entry(input);
+            let entry = if masked {
+                entry | ENTRY_MASKED
+            } else {
+                entry & !ENTRY_MASKED
+            };
+
+            io_apic.write_entry(input, entry);
+        }
+    });
+}
@@ -0,0 +1,97 @@
+use super::lapic;
+
+const DELIVERY_FIXED: u32 = 0b000 << 8;
+const DELIVERY_NMI: u32 = 0b100 << 8;
+const DELIVERY_INIT: u32 = 0b101 << 8;
+const DELIVERY_STARTUP: u32 = 0b110 << 8;
+
+const LEVEL_ASSERT: u32 = 1 << 14;
+
+const SHORTHAND_SELF: u32 = 0b01 << 18;
+const SHORTHAND_ALL: u32 = 0b10 << 18;
+const SHORTHAND_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+/// The CPUs an IPI is sent to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+    /// The CPU with the given local APIC id.
+    Cpu(u32),
+    /// The calling CPU.
+    Current,
+    /// Every CPU, including the calling one.
+    All,
+    /// Every CPU except the calling one.
+    AllExcludingCurrent,
+}
+
+impl Destination {
+    /// Returns the APIC id and the shorthand bits of the ICR.
+    fn encode(self) -> (u32, u32) {
+        match self {
+            Destination::Cpu(apic_id) => (apic_id, 0),
+            Destination::Current => (0, SHORTHAND_SELF),
+            Destination::All => (0, SHORTHAND_ALL),
+            Destination::AllExcludingCurrent => (0, SHORTHAND_ALL_EXCLUDING_SELF),
+        }
+    }
+}
+
+/// Raises the given vector on the destination CPUs.
+///
+/// # Arguments
+/// * `destination` - The CPUs to interrupt.
+/// * `vector` - The vector raised on the destination CPUs.
+///
+pub fn send(destination: Destination, vector: u8) {
+    assert!(
+        vector >= 32,
+        "Cannot send exception vector {} as an IPI",
+        vector
+    );
+
+    let (apic_id, shorthand) = destination.encode();
+    unsafe {
+        lapic::send_ipi(
+            apic_id,
+            shorthand | LEVEL_ASSERT | DELIVERY_FIXED | vector as u32,
+        )
+    };
+}
+
+/// Sends a non-maskable interrupt to the destination CPUs.
+///
+/// # Arguments
+/// * `destination` - The CPUs to interrupt.
+///
+pub fn send_nmi(destination: Destination) {
+    let (apic_id, shorthand) = destination.encode();
+    unsafe { lapic::send_ipi(apic_id, shorthand | LEVEL_ASSERT | DELIVERY_NMI) };
+}
+
+/// Sends an INIT IPI, which puts the CPU into the wait-for-SIPI state.
+///
+/// # Arguments
+/// * `apic_id` - The local APIC id of the CPU to reset.
+///
+/// # Safety
+///
+/// The CPU loses all of its state, it must not be running anything.
+///
+pub unsafe fn send_init(apic_id: u32) {
+    lapic::send_ipi(apic_id, LEVEL_ASSERT | DELIVERY_INIT);
+}
+
+/// Sends a startup IPI, the CPU starts executing in real mode at `page * 4096`.
+///
+/// # Arguments
+/// * `apic_id` - The local APIC id of the CPU to start.
+/// * `page` - The page of the real mode entry point, below 1 MiB.
+///
+/// # Safety
+///
+/// The CPU has to be in the wait-for-SIPI state and a valid trampoline
+/// must be present at the given page.
+///
+pub unsafe fn send_startup(apic_id: u32, page: u8) {
+    lapic::send_ipi(apic_id, LEVEL_ASSERT | DELIVERY_STARTUP | page as u32);
+}
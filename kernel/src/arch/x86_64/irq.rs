@@ -0,0 +1,180 @@
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use alloc::{sync::Arc, vec::Vec};
+use spin::RwLock;
+
+use super::{idt::InterruptGuard, interrupts::InterruptContext, lapic};
+
+/// A handler for a hardware or software interrupt.
+pub type IrqHandler = fn(context: &InterruptContext);
+
+/// The first vector that isn't a CPU exception.
+pub const FIRST_VECTOR: u8 = 32;
+
+/// The vectors handed out by `allocate_vector`, everything outside of this
+/// range is assigned statically (legacy PIC, local APIC, IPIs).
+pub const DYNAMIC_VECTORS: Range<u8> = 0x40..0xF0;
+
+const VECTOR_COUNT: usize = 256 - FIRST_VECTOR as usize;
+
+struct Vector {
+    /// Every handler gets called, a vector may be shared by several devices.
+    ///
+    /// Changing the handlers replaces the whole list, so `dispatch` only has
+    /// to take a reference and never holds the lock while handlers run.
+    ///
+    handlers: RwLock<Option<Arc<[IrqHandler]>>>,
+    count: AtomicU64,
+    allocated: AtomicBool,
+}
+
+impl Vector {
+    const fn new() -> Self {
+        Self {
+            handlers: RwLock::new(None),
+            count: AtomicU64::new(0),
+            allocated: AtomicBool::new(false),
+        }
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const VECTOR_INIT: Vector = Vector::new();
+
+static VECTORS: [Vector; VECTOR_COUNT] = [VECTOR_INIT; VECTOR_COUNT];
+
+fn entry(vector: u8) -> &'static Vector {
+    assert!(
+        vector >= FIRST_VECTOR,
+        "Vector {} is reserved for exceptions",
+        vector
+    );
+
+    &VECTORS[(vector - FIRST_VECTOR) as usize]
+}
+
+/// Runs `f` with interrupts disabled on the calling CPU.
+///
+/// The handler lists are read from interrupt context, so they must never be
+/// locked for writing while an interrupt could arrive on the same CPU.
+///
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let _interrupts = InterruptGuard::new();
+    f()
+}
+
+/// Allocates an unused vector from `DYNAMIC_VECTORS`.
+///
+/// Returns `None` if every vector is taken.
+///
+pub fn allocate_vector() -> Option<u8> {
+    let mut vectors = DYNAMIC_VECTORS;
+
+    vectors.find(|&vector| {
+        entry(vector)
+            .allocated
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+    })
+}
+
+/// Returns a vector obtained from `allocate_vector`.
+///
+/// Every handler has to be unregistered before.
+///
+pub fn free_vector(vector: u8) {
+    let entry = entry(vector);
+
+    assert!(
+        entry.handlers.read().is_none(),
+        "Freeing vector {:#x} which still has handlers",
+        vector
+    );
+    assert!(
+        entry.allocated.swap(false, Ordering::AcqRel),
+        "Double free of vector {:#x}",
+        vector
+    );
+}
+
+/// Registers a handler for the given vector.
+///
+/// The local APIC is acknowledged automatically once every handler of the
+/// vector has run.
+///
+/// # Arguments
+/// * `vector` - The vector to handle, either allocated or statically assigned.
+/// * `handler` - The handler, called with interrupts disabled.
+///
+pub fn register_irq(vector: u8, handler: IrqHandler) {
+    let entry = entry(vector);
+
+    without_interrupts(|| {
+        let mut handlers = entry.handlers.write();
+
+        let mut list = handlers.as_deref().map_or(Vec::new(), <[_]>::to_vec);
+        list.push(handler);
+
+        *handlers = Some(list.into());
+    });
+
+    trace!("Registered handler for vector {:#x}", vector);
+}
+
+/// Removes a handler previously registered with `register_irq`.
+///
+/// Returns `false` if the handler wasn't registered for the given vector.
+///
+/// # Arguments
+/// * `vector` - The vector the handler was registered for.
+/// * `handler` - The handler to remove.
+///
+pub fn unregister_irq(vector: u8, handler: IrqHandler) -> bool {
+    let entry = entry(vector);
+
+    without_interrupts(|| {
+        let mut handlers = entry.handlers.write();
+
+        let mut list = handlers.as_deref().map_or(Vec::new(), <[_]>::to_vec);
+        let Some(position) = list.iter().position(|&h| h as usize == handler as usize) else {
+            return false;
+        };
+
+        list.remove(position);
+        *handlers = (!list.is_empty()).then(|| list.into());
+        true
+    })
+}
+
+/// Returns how often the given vector was raised so far, on all CPUs combined.
+///
+pub fn irq_count(vector: u8) -> u64 {
+    entry(vector).count.load(Ordering::Relaxed)
+}
+
+/// Handles every vector that isn't a CPU exception.
+pub(super) fn dispatch(context: &InterruptContext) {
+    let vector = context.vector as u8;
+    let entry = entry(vector);
+
+    entry.count.fetch_add(1, Ordering::Relaxed);
+
+    // Handlers may (un)register handlers themselves, so only a reference to
+    // the current list is taken
+    let handlers = entry.handlers.read().clone();
+
+    let Some(handlers) = handlers else {
+        trace!("Unhandled interrupt on vector {:#x}", vector);
+        lapic::eoi();
+        return;
+    };
+
+    for handler in handlers.iter() {
+        handler(context);
+    }
+
+    lapic::eoi();
+}
@@ -0,0 +1,303 @@
+use core::{
+    arch::x86_64::__cpuid,
+    ptr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use spin::Once;
+use x86::msr::{rdmsr, wrmsr, IA32_APIC_BASE};
+
+use super::{
+    idt::{self, ExceptionStackFrame, Handler},
+    interrupts::InterruptContext,
+    irq, mmu, pic, pit,
+};
+use crate::arch::{PhysicalAddress, VirtualAddress};
+
+/// The vector the local APIC timer fires at.
+pub const TIMER_VECTOR: u8 = 0x30;
+
+/// The vector local APIC errors are reported at.
+pub const ERROR_VECTOR: u8 = 0xFE;
+
+/// The vector spurious interrupts arrive at, the lowest four bits have to be set
+/// on older CPUs.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// The offsets of the registers in the xAPIC MMIO page.
+///
+/// In x2APIC mode every register is a MSR at `0x800 + offset / 16` instead.
+///
+mod reg {
+    pub const ID: u32 = 0x020;
+    pub const VERSION: u32 = 0x030;
+    pub const TASK_PRIORITY: u32 = 0x080;
+    pub const EOI: u32 = 0x0B0;
+    pub const SPURIOUS: u32 = 0x0F0;
+    pub const ERROR_STATUS: u32 = 0x280;
+    pub const ICR_LOW: u32 = 0x300;
+    pub const ICR_HIGH: u32 = 0x310;
+    pub const LVT_TIMER: u32 = 0x320;
+    pub const LVT_LINT0: u32 = 0x350;
+    pub const LVT_ERROR: u32 = 0x370;
+    pub const TIMER_INITIAL_COUNT: u32 = 0x380;
+    pub const TIMER_CURRENT_COUNT: u32 = 0x390;
+    pub const TIMER_DIVIDE: u32 = 0x3E0;
+}
+
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+
+/// Set in the ICR while the xAPIC has not accepted the last IPI yet.
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+
+/// The MSR of the whole 64 bit ICR in x2APIC mode.
+const X2APIC_ICR: u32 = 0x830;
+
+/// Divides the timer input clock by 16.
+const TIMER_DIVIDE_16: u32 = 0b0011;
+
+/// How long the timer gets calibrated for.
+const CALIBRATION_MS: u64 = 10;
+
+/// How the local APIC registers are accessed.
+#[derive(Debug, Clone, Copy)]
+enum Mode {
+    /// Memory mapped registers at the given address.
+    XApic(VirtualAddress),
+    /// Registers are accessed through MSRs.
+    X2Apic,
+}
+
+static MODE: Once<Mode> = Once::new();
+
+/// The frequency of the timer (after dividing) in Hz, the same on every CPU.
+static TIMER_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// The number of timer interrupts handled by all CPUs.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+fn mode() -> Mode {
+    *MODE.get().expect("Local APIC is not initialized yet")
+}
+
+unsafe fn read(reg: u32) -> u32 {
+    match mode() {
+        Mode::XApic(base) => ptr::read_volatile((base.as_u64() + reg as u64) as *const u32),
+        Mode::X2Apic => rdmsr(0x800 + (reg >> 4)) as u32,
+    }
+}
+
+unsafe fn write(reg: u32, value: u32) {
+    match mode() {
+        Mode::XApic(base) => ptr::write_volatile((base.as_u64() + reg as u64) as *mut u32, value),
+        Mode::X2Apic => wrmsr(0x800 + (reg >> 4), value as u64),
+    }
+}
+
+/// Returns whether the CPU supports the x2APIC.
+pub fn supports_x2apic() -> bool {
+    unsafe { __cpuid(1).ecx & (1 << 21) != 0 }
+}
+
+/// Returns whether the local APIC is accessed through MSRs.
+pub fn is_x2apic() -> bool {
+    matches!(mode(), Mode::X2Apic)
+}
+
+/// Disables the legacy PIC, enables the local APIC of the BSP and calibrates its timer.
+///
+/// Has to be called after the kernel page tables are set up, the xAPIC
+/// registers are mapped through them.
+///
+pub unsafe fn init() {
+    pic::disable();
+
+    MODE.call_once(|| {
+        if supports_x2apic() {
+            Mode::X2Apic
+        } else {
+            let base = PhysicalAddress::new(rdmsr(IA32_APIC_BASE) & APIC_BASE_ADDRESS_MASK);
+            Mode::XApic(mmu::map_mmio(base, 0x1000))
+        }
+    });
+
+    irq::register_irq(TIMER_VECTOR, timer_handler);
+    irq::register_irq(ERROR_VECTOR, error_handler);
+
+    // Spurious interrupts bypass the common dispatcher, they must not be acknowledged
+    idt::set_handler(Handler::Interrupt(SPURIOUS_VECTOR, spurious_handler));
+
+    for vector in pic::SPURIOUS_VECTORS {
+        idt::set_handler(Handler::Interrupt(vector, spurious_handler));
+    }
+
+    enable();
+    calibrate_timer();
+
+    info!(
+        "Local APIC {} enabled ({}, version {:#x}, timer at {} Hz)",
+        id(),
+        if is_x2apic() { "x2APIC" } else { "xAPIC" },
+        read(reg::VERSION) & 0xFF,
+        TIMER_FREQUENCY.load(Ordering::Relaxed)
+    );
+}
+
+/// Enables the local APIC of the calling CPU.
+///
+/// # Safety
+///
+/// `init` must have been called on the BSP before.
+///
+pub unsafe fn enable() {
+    let base = rdmsr(IA32_APIC_BASE) | APIC_BASE_ENABLE;
+    wrmsr(IA32_APIC_BASE, base);
+
+    // The x2APIC can only be entered once the xAPIC is enabled
+    if is_x2apic() {
+        wrmsr(IA32_APIC_BASE, base | APIC_BASE_X2APIC);
+    }
+
+    // Accept every interrupt priority
+    write(reg::TASK_PRIORITY, 0);
+
+    write(reg::LVT_TIMER, LVT_MASKED | TIMER_VECTOR as u32);
+    write(reg::LVT_LINT0, LVT_MASKED);
+    write(reg::LVT_ERROR, ERROR_VECTOR as u32);
+
+    // Clear errors from before we took over
+    write(reg::ERROR_STATUS, 0);
+    write(reg::ERROR_STATUS, 0);
+
+    write(reg::SPURIOUS, SPURIOUS_ENABLE | SPURIOUS_VECTOR as u32);
+
+    eoi();
+}
+
+/// Returns the local APIC id of the calling CPU.
+pub fn id() -> u32 {
+    let id = unsafe { read(reg::ID) };
+
+    if is_x2apic() {
+        id
+    } else {
+        id >> 24
+    }
+}
+
+/// Signals the end of an interrupt to the local APIC of the calling CPU.
+pub fn eoi() {
+    unsafe {
+        write(reg::EOI, 0);
+    }
+}
+
+/// Writes the interrupt command register, sending an IPI.
+///
+/// # Arguments
+/// * `destination` - The local APIC id of the target, ignored when `command` uses a shorthand.
+/// * `command` - The lower 32 bits of the ICR (vector, delivery mode and shorthand).
+///
+/// # Safety
+///
+/// The command must not disturb the target CPUs (INIT resets them).
+///
+pub unsafe fn send_ipi(destination: u32, command: u32) {
+    match mode() {
+        Mode::XApic(_) => irq::without_interrupts(|| {
+            // Writing the lower half sends the IPI, an interrupt handler
+            // sending an IPI of its own must not sneak in between
+            write(reg::ICR_HIGH, destination << 24);
+            write(reg::ICR_LOW, command);
+
+            while read(reg::ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+        }),
+        Mode::X2Apic => wrmsr(X2APIC_ICR, (destination as u64) << 32 | command as u64),
+    }
+}
+
+/// Measures the timer frequency by letting it run for a few milliseconds of
+/// the PIT channel 2.
+unsafe fn calibrate_timer() {
+    write(reg::TIMER_DIVIDE, TIMER_DIVIDE_16);
+    write(reg::LVT_TIMER, LVT_MASKED | TIMER_VECTOR as u32);
+
+    // Start both at the same time
+    pit::wait_ms(CALIBRATION_MS, || write(reg::TIMER_INITIAL_COUNT, u32::MAX));
+
+    let elapsed = u32::MAX - read(reg::TIMER_CURRENT_COUNT);
+    write(reg::TIMER_INITIAL_COUNT, 0);
+
+    TIMER_FREQUENCY.store(elapsed as u64 * 1000 / CALIBRATION_MS, Ordering::Relaxed);
+}
+
+/// Converts a duration in nanoseconds into timer ticks.
+fn timer_count(ns: u64) -> u32 {
+    let frequency = TIMER_FREQUENCY.load(Ordering::Relaxed) as u128;
+    let count = frequency * ns as u128 / 1_000_000_000;
+
+    count.clamp(1, u32::MAX as u128) as u32
+}
+
+/// Starts the timer of the calling CPU in periodic mode.
+///
+/// # Arguments
+/// * `hz` - How many times a second the timer fires.
+///
+pub fn start_periodic(hz: u64) {
+    unsafe {
+        write(reg::TIMER_DIVIDE, TIMER_DIVIDE_16);
+        write(reg::LVT_TIMER, LVT_TIMER_PERIODIC | TIMER_VECTOR as u32);
+        write(reg::TIMER_INITIAL_COUNT, timer_count(1_000_000_000 / hz));
+    }
+}
+
+/// Lets the timer of the calling CPU fire once.
+///
+/// # Arguments
+/// * `ns` - The time until the timer fires in nanoseconds.
+///
+pub fn start_oneshot(ns: u64) {
+    unsafe {
+        write(reg::TIMER_DIVIDE, TIMER_DIVIDE_16);
+        write(reg::LVT_TIMER, TIMER_VECTOR as u32);
+        write(reg::TIMER_INITIAL_COUNT, timer_count(ns));
+    }
+}
+
+/// Stops the timer of the calling CPU.
+pub fn stop_timer() {
+    unsafe {
+        write(reg::LVT_TIMER, LVT_MASKED | TIMER_VECTOR as u32);
+        write(reg::TIMER_INITIAL_COUNT, 0);
+    }
+}
+
+/// Returns the number of timer interrupts handled so far.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+fn timer_handler(_context: &InterruptContext) {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+fn error_handler(_context: &InterruptContext) {
+    let status = unsafe {
+        // The error status register has to be written before it can be read
+        write(reg::ERROR_STATUS, 0);
+        read(reg::ERROR_STATUS)
+    };
+
+    error!("Local APIC {} error: {:#x}", id(), status);
+}
+
+extern "x86-interrupt" fn spurious_handler(_frame: ExceptionStackFrame) {}
@@ -1,108 +1,861 @@
-use alloc::boxed::Box;
+use core::arch::x86_64::__cpuid;
+
+use alloc::{boxed::Box, vec::Vec};
+use limine::{
+    LimineFramebufferRequest, LimineHhdmRequest, LimineKernelAddressRequest,
+    LimineMemoryMapEntryType,
+};
 use x86::{
-    controlregs::{cr3, cr3_write},
+    controlregs::{cr0, cr0_write, cr3, cr3_write, cr4, cr4_write, Cr0, Cr4},
     current::paging::*,
+    msr::{rdmsr, wrmsr, IA32_EFER},
+    tlb,
 };
 
+use super::{
+    percpu, shootdown,
+    smp::{self, MAX_CPUS},
+};
 use crate::{
-    allocator::{allocate_pages, FrameAllocator},
-    arch::MemoryMapper,
+    allocator::{allocate_pages, deallocate_pages, memory_map, try_allocate_pages, FrameAllocator},
+    arch::{MemoryMapper, PageFlags, PageSize, PhysicalAddress, Translation, VirtualAddress},
+    sync::IrqSpinlock,
 };
 
+static HHDM_REQUEST: LimineHhdmRequest = LimineHhdmRequest::new(0);
+static KERNEL_ADDRESS_REQUEST: LimineKernelAddressRequest = LimineKernelAddressRequest::new(0);
+static FRAMEBUFFER_REQUEST: LimineFramebufferRequest = LimineFramebufferRequest::new(0);
+
+/// The flags every page of the higher half direct map is mapped with.
+const HHDM_FLAGS: PageFlags = PageFlags::WRITABLE
+    .union(PageFlags::NO_EXECUTE)
+    .union(PageFlags::GLOBAL);
+
+/// The no-execute enable bit of the `IA32_EFER` MSR.
+const EFER_NXE: u64 = 1 << 11;
+
+extern "C" {
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+}
+
+lazy_static! {
+    /// The offset at which limine maps the entire physical memory.
+    static ref HHDM_OFFSET: u64 = HHDM_REQUEST
+        .get_response()
+        .get()
+        .expect("Bootloader did not provide a higher half direct map")
+        .offset;
+}
+
+/// Returns the offset of the higher half direct map.
+///
+/// Every physical address `phys` is accessible at `phys + hhdm_offset()`.
+///
+pub fn hhdm_offset() -> u64 {
+    *HHDM_OFFSET
+}
+
+/// Returns the physical address of the PML4 loaded on the calling CPU.
+pub fn active_pml4() -> PhysicalAddress {
+    PhysicalAddress::new(unsafe { cr3() } & ADDRESS_MASK)
+}
+
+/// Returns a pointer to the paging structure at the given physical address.
+fn table_ptr<T>(addr: PAddr) -> *mut T {
+    PhysicalAddress::from(addr).to_virtual().as_mut_ptr()
+}
+
+/// The page tables of the kernel.
+///
+/// Every address space shares the higher half of these tables. Like the
+/// other locks of this module it is held with interrupts disabled, a thread
+/// preempted while holding it would stall every CPU spinning on it.
+///
+pub static KERNEL_MAPPER: IrqSpinlock<Option<X64MemoryMapper>> = IrqSpinlock::new(None);
+
+/// A range of virtual memory that is backed by zeroed frames on first access.
+#[derive(Debug, Clone, Copy)]
+struct DemandRegion {
+    start: u64,
+    end: u64,
+    flags: PageFlags,
+}
+
+static DEMAND_REGIONS: IrqSpinlock<Vec<DemandRegion>> = IrqSpinlock::new(Vec::new());
+
+/// Reserves a range of virtual memory that gets backed by physical memory
+/// the first time each of its pages is accessed.
+///
+/// The pages are mapped into the kernel page tables, so the region has to
+/// lie in the higher half every address space shares.
+///
+/// # Arguments
+/// * `start` - The page aligned start of the region.
+/// * `size` - The size of the region in bytes.
+/// * `flags` - The flags the pages of the region are mapped with.
+///
+pub fn map_on_demand(start: VirtualAddress, size: u64, flags: PageFlags) {
+    assert!(start.as_u64() % BASE_PAGE_SIZE as u64 == 0);
+    assert!(
+        KERNEL_PML4_ENTRIES.contains(&TableIndices::new(start).pml4),
+        "Demand paged region {:#x} is not in the higher half",
+        start.as_u64()
+    );
+
+    trace!(
+        "Demand paging {:#x} - {:#x}",
+        start.as_u64(),
+        start.as_u64() + size
+    );
+
+    DEMAND_REGIONS.lock().push(DemandRegion {
+        start: start.as_u64(),
+        end: start.as_u64() + size,
+        flags,
+    });
+}
+
+/// The flags memory mapped I/O is mapped with, device registers must never be cached.
+const MMIO_FLAGS: PageFlags = HHDM_FLAGS
+    .union(PageFlags::CACHE_DISABLE)
+    .union(PageFlags::WRITE_THROUGH);
+
+/// Maps a range of device memory into the higher half direct map.
+///
+/// Device memory usually isn't part of the memory map, so it is missing from
+/// the direct map until it gets mapped here. Pages that are already mapped
+/// are left untouched.
+///
+/// # Arguments
+/// * `phys` - The physical start address of the range.
+/// * `size` - The size of the range in bytes.
+///
+/// # Safety
+///
+/// The range must not contain regular memory, it would end up mapped with
+/// conflicting cache types.
+///
+pub unsafe fn map_mmio(phys: PhysicalAddress, size: u64) -> VirtualAddress {
+    map_direct(phys, size, MMIO_FLAGS)
+}
+
+/// Maps a range of firmware memory (like ACPI tables) into the higher half direct map.
+///
+/// Unlike `map_mmio`, the range is mapped cacheable. Pages that are already
+/// mapped are left untouched.
+///
+/// # Arguments
+/// * `phys` - The physical start address of the range.
+/// * `size` - The size of the range in bytes.
+///
+/// # Safety
+///
+/// The range must not contain device memory.
+///
+pub unsafe fn map_physical_region(phys: PhysicalAddress, size: u64) -> VirtualAddress {
+    map_direct(phys, size, HHDM_FLAGS)
+}
+
+unsafe fn map_direct(phys: PhysicalAddress, size: u64, flags: PageFlags) -> VirtualAddress {
+    let start = PAddr::from(phys).align_down_to_base_page().as_u64();
+    let end = PAddr::from(phys.as_u64() + size)
+        .align_up_to_base_page()
+        .as_u64();
+
+    let mut guard = KERNEL_MAPPER.lock();
+    let mapper = guard
+        .as_mut()
+        .expect("Kernel page tables are not initialized yet");
+
+    for page in (start..end).step_by(BASE_PAGE_SIZE) {
+        let page = PhysicalAddress::new(page);
+        if mapper.translate(page.to_virtual()).is_none() {
+            mapper.map(page, page.to_virtual(), PageSize::Size4KiB, flags);
+        }
+    }
+
+    phys.to_virtual()
+}
+
+/// The virtual memory kernel stacks are mapped into, outside of the higher
+/// half direct map so they can be surrounded by unmapped guard pages.
+const KERNEL_STACKS_START: u64 = 0xFFFF_FE00_0000_0000;
+const KERNEL_STACKS_END: u64 = 0xFFFF_FF00_0000_0000;
+
+/// The flags kernel stacks are mapped with.
+const KERNEL_STACK_FLAGS: PageFlags = PageFlags::WRITABLE
+    .union(PageFlags::NO_EXECUTE)
+    .union(PageFlags::GLOBAL);
+
+struct KernelStackArea {
+    /// The start of the memory that was never handed out.
+    next: u64,
+    /// Previously freed ranges as (start, pages), guard page included.
+    free: Vec<(u64, usize)>,
+}
+
+static KERNEL_STACKS: IrqSpinlock<KernelStackArea> = IrqSpinlock::new(KernelStackArea {
+    next: KERNEL_STACKS_START,
+    free: Vec::new(),
+});
+
+/// Maps a kernel stack with an unmapped guard page below it.
+///
+/// Returns the lowest address of the stack, a stack overflow faults on the
+/// guard page instead of overwriting whatever lies below.
+///
+/// # Arguments
+/// * `pages` - The size of the stack in pages, without the guard page.
+///
+pub fn map_kernel_stack(pages: usize) -> VirtualAddress {
+    let slot_pages = pages + 1;
+
+    let slot = {
+        let mut area = KERNEL_STACKS.lock();
+
+        match area.free.iter().position(|&(_, size)| size == slot_pages) {
+            Some(index) => area.free.swap_remove(index).0,
+            None => {
+                let slot = area.next;
+                area.next += (slot_pages * BASE_PAGE_SIZE) as u64;
+
+                assert!(
+                    area.next <= KERNEL_STACKS_END,
+                    "Out of virtual memory for kernel stacks"
+                );
+
+                slot
+            }
+        }
+    };
+
+    let bottom = slot + BASE_PAGE_SIZE as u64;
+
+    let mut guard = KERNEL_MAPPER.lock();
+    let mapper = guard
+        .as_mut()
+        .expect("Kernel page tables are not initialized yet");
+
+    for page in 0..pages {
+        unsafe {
+            let frame = allocate_pages(1);
+            let virt = VirtualAddress::new(bottom + (page * BASE_PAGE_SIZE) as u64);
+
+            mapper.map(frame.into(), virt, PageSize::Size4KiB, KERNEL_STACK_FLAGS);
+        }
+    }
+
+    VirtualAddress::new(bottom)
+}
+
+/// Unmaps a stack mapped by `map_kernel_stack` and frees its frames.
+///
+/// # Arguments
+/// * `bottom` - The address returned by `map_kernel_stack`.
+/// * `pages` - The size of the stack in pages, without the guard page.
+///
+/// # Safety
+///
+/// The stack must not be in use anymore, by any CPU.
+///
+pub unsafe fn unmap_kernel_stack(bottom: VirtualAddress, pages: usize) {
+    let (stale, frames) = {
+        let mut guard = KERNEL_MAPPER.lock();
+        let mapper = guard
+            .as_mut()
+            .expect("Kernel page tables are not initialized yet");
+
+        let frames = (0..pages)
+            .filter_map(|page| {
+                let virt = VirtualAddress::new(bottom.as_u64() + (page * BASE_PAGE_SIZE) as u64);
+                mapper.unmap(virt)
+            })
+            .collect::<Vec<_>>();
+
+        (mapper.take_stale(), frames)
+    };
+
+    // The frames may only be reused once no CPU can reach them anymore
+    stale.shoot_down();
+
+    for (frame, _) in frames {
+        deallocate_pages(frame.into(), 1);
+    }
+
+    let slot = bottom.as_u64() - BASE_PAGE_SIZE as u64;
+    KERNEL_STACKS.lock().free.push((slot, pages + 1));
+}
+
+/// The outcome of `handle_demand_fault`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum DemandFault {
+    /// The page was backed with a fresh frame.
+    Mapped,
+    /// The address does not belong to a demand paged region.
+    NotDemandPaged,
+    /// The address belongs to a demand paged region, but no frame was free.
+    OutOfMemory,
+}
+
+/// Backs the page containing `virt` with a zeroed frame if it is part of a
+/// demand paged region.
+///
+/// # Safety
+///
+/// Must only be called for page faults on not present pages.
+///
+pub(super) unsafe fn handle_demand_fault(virt: VirtualAddress) -> DemandFault {
+    let region = DEMAND_REGIONS
+        .lock()
+        .iter()
+        .find(|region| (region.start..region.end).contains(&virt.as_u64()))
+        .copied();
+
+    let Some(region) = region else {
+        return DemandFault::NotDemandPaged;
+    };
+
+    let Some(frame) = try_allocate_pages(1) else {
+        return DemandFault::OutOfMemory;
+    };
+
+    let frame = PhysicalAddress::from(frame);
+    frame
+        .to_virtual()
+        .as_mut_ptr::<u8>()
+        .write_bytes(0, BASE_PAGE_SIZE);
+
+    let page = VirtualAddress::new(virt.as_u64() & !(BASE_PAGE_SIZE as u64 - 1));
+
+    let mut guard = KERNEL_MAPPER.lock();
+    let mapper = guard
+        .as_mut()
+        .expect("Kernel page tables are not initialized yet");
+
+    // Another CPU may have faulted on the same page and won the race
+    if mapper.translate(page).is_some() {
+        drop(guard);
+        deallocate_pages(frame.into(), 1);
+    } else {
+        mapper.map(frame, page, PageSize::Size4KiB, region.flags);
+    }
+
+    DemandFault::Mapped
+}
+
+/// Builds the kernel's own page tables and switches to them.
+///
+/// The new tables map the kernel image (with the permissions of its ELF
+/// segments), the higher half direct map and the framebuffer. Afterwards
+/// nothing depends on the bootloader's page tables anymore.
+///
 pub fn init() {
     unsafe {
-        // For now, we'll just use limine's memory map
-        let mut mapper = X64MemoryMapper::from_active();
+        enable_paging_features();
+
+        let mut mapper = X64MemoryMapper::new();
+
+        map_kernel_image(&mut mapper);
+        map_hhdm(&mut mapper);
+        map_framebuffers(&mut mapper);
+        allocate_higher_half(&mut mapper);
 
         mapper.submit();
+
+        *KERNEL_MAPPER.lock() = Some(mapper);
     }
+
+    info!("Switched to kernel page tables");
 }
 
-#[repr(transparent)]
-pub struct X64MemoryMapper {
-    pml4: &'static mut PML4,
+/// Creates the page tables of a new user address space.
+///
+/// The lower half starts out empty, the higher half is shared with the
+/// kernel page tables.
+///
+pub fn new_address_space() -> X64MemoryMapper {
+    unsafe {
+        let mut mapper = X64MemoryMapper::new();
+
+        let guard = KERNEL_MAPPER.lock();
+        let kernel = guard
+            .as_ref()
+            .expect("Kernel page tables are not initialized yet");
+
+        mapper.pml4[KERNEL_PML4_ENTRIES].copy_from_slice(&kernel.pml4[KERNEL_PML4_ENTRIES]);
+
+        mapper
+    }
 }
 
-impl X64MemoryMapper {
-    unsafe fn get_pdpt(&mut self, pml4_idx: usize) -> Option<*mut PDPT> {
-        let pml4 = &mut self.pml4[pml4_idx];
+/// Frees the page tables of an address space created by `new_address_space`,
+/// together with every frame mapped into its lower half.
+///
+/// The higher half is shared with the kernel page tables and stays untouched.
+///
+/// # Safety
+///
+/// The address space must not be active on any CPU and has to own every
+/// frame mapped into its lower half.
+///
+pub unsafe fn destroy_address_space(mapper: X64MemoryMapper) {
+    for entry in mapper.pml4[..KERNEL_PML4_ENTRIES.start].iter() {
+        if entry.is_present() {
+            free_table(PAddr(entry.0 & ADDRESS_MASK), 3);
+        }
+    }
 
-        if pml4.is_present() {
-            Some(pml4.address().0 as *mut PDPT)
-        } else {
-            None
+    deallocate_pages(mapper.pml4_address().into(), 1);
+}
+
+/// Frees a paging structure of the lower half, the tables below it and
+/// every page they map.
+///
+/// # Arguments
+/// * `table` - The physical address of the table.
+/// * `level` - 3 for a PDPT, 2 for a PD and 1 for a PT.
+///
+unsafe fn free_table(table: PAddr, level: u8) {
+    let entries = &*table_ptr::<[u64; 512]>(table);
+
+    for &entry in entries.iter() {
+        if entry & PTFlags::P.bits() == 0 {
+            continue;
+        }
+
+        if level > 1 && entry & HUGE_PAGE == 0 {
+            free_table(PAddr(entry & ADDRESS_MASK), level - 1);
+            continue;
         }
+
+        let size = match level {
+            3 => PageSize::Size1GiB,
+            2 => PageSize::Size2MiB,
+            _ => PageSize::Size4KiB,
+        };
+
+        deallocate_pages(
+            PAddr(leaf_address(entry, size)),
+            (size.bytes() / BASE_PAGE_SIZE as u64) as usize,
+        );
     }
 
-    unsafe fn get_pd(&mut self, pml4_idx: usize, pdp_idx: usize) -> Option<*mut PD> {
-        let pdpt = self.get_pdpt(pml4_idx)?;
+    deallocate_pages(table, 1);
+}
+
+/// Switches the calling AP to the kernel page tables built by `init`.
+///
+/// # Safety
+///
+/// `init` must have been called on the BSP before.
+///
+pub unsafe fn init_ap() {
+    enable_paging_features();
 
-        let pdpt = &mut *pdpt;
-        let pdp = &mut pdpt[pdp_idx];
+    let pml4 = KERNEL_MAPPER
+        .lock()
+        .as_ref()
+        .expect("Kernel page tables are not set up yet")
+        .pml4_address();
 
-        if pdp.is_present() {
-            Some(pdp.address().0 as *mut PD)
-        } else {
-            None
+    cr3_write(pml4.as_u64());
+}
+
+/// Sets up the paging related control registers of the calling CPU.
+unsafe fn enable_paging_features() {
+    // Respect the NX bit and forbid the kernel from writing to read-only pages
+    wrmsr(IA32_EFER, rdmsr(IA32_EFER) | EFER_NXE);
+    cr0_write(cr0() | Cr0::CR0_WRITE_PROTECT);
+
+    // The kernel is mapped global so its TLB entries survive address space switches
+    cr4_write(cr4() | Cr4::CR4_ENABLE_GLOBAL_PAGES);
+}
+
+unsafe fn map_kernel_image(mapper: &mut X64MemoryMapper) {
+    let kernel_address = KERNEL_ADDRESS_REQUEST
+        .get_response()
+        .get()
+        .expect("Bootloader did not provide the kernel address");
+
+    let segments = [
+        (
+            "text",
+            &__text_start as *const u8,
+            &__text_end as *const u8,
+            PageFlags::GLOBAL,
+        ),
+        (
+            "rodata",
+            &__rodata_start as *const u8,
+            &__rodata_end as *const u8,
+            PageFlags::GLOBAL | PageFlags::NO_EXECUTE,
+        ),
+        (
+            "data",
+            &__data_start as *const u8,
+            &__data_end as *const u8,
+            PageFlags::GLOBAL | PageFlags::WRITABLE | PageFlags::NO_EXECUTE,
+        ),
+    ];
+
+    for (name, start, end, flags) in segments {
+        let start = VAddr::from(start as u64).align_down_to_base_page();
+        let end = VAddr::from(end as u64).align_up_to_base_page();
+
+        // Limine loads the kernel physically contiguous
+        let phys_start =
+            start.as_u64() - kernel_address.virtual_base + kernel_address.physical_base;
+
+        trace!(
+            "Mapping kernel .{} {:#x} - {:#x} -> {:#x}",
+            name,
+            start,
+            end,
+            phys_start
+        );
+
+        for offset in (0..end.as_u64() - start.as_u64()).step_by(BASE_PAGE_SIZE) {
+            mapper.map(
+                PhysicalAddress::new(phys_start + offset),
+                VirtualAddress::new(start.as_u64() + offset),
+                PageSize::Size4KiB,
+                flags,
+            );
         }
     }
+}
 
-    unsafe fn get_pt(&mut self, pml4_idx: usize, pdp_idx: usize, pd_idx: usize) -> Option<*mut PT> {
-        let pd = self.get_pd(pml4_idx, pdp_idx)?;
+/// The PML4 entries covering the higher half.
+const KERNEL_PML4_ENTRIES: core::ops::RangeFrom<usize> = 256..;
 
-        let pd = &mut *pd;
-        let pt = &mut pd[pd_idx];
+/// Fills every higher half PML4 entry, so kernel mappings made later show up
+/// in every address space copying these entries.
+unsafe fn allocate_higher_half(mapper: &mut X64MemoryMapper) {
+    for entry in mapper.pml4[KERNEL_PML4_ENTRIES].iter_mut() {
+        X64MemoryMapper::next_table_or_create::<PDPT>(&mut entry.0, false);
+    }
+}
 
-        if pt.is_present() {
-            Some(pt.address().0 as *mut PT)
-        } else {
-            None
+unsafe fn map_hhdm(mapper: &mut X64MemoryMapper) {
+    for entry in memory_map() {
+        let flags = match entry.typ {
+            LimineMemoryMapEntryType::BadMemory => continue,
+
+            // Reserved memory may contain memory mapped devices
+            LimineMemoryMapEntryType::Reserved => MMIO_FLAGS,
+
+            // Video memory must not be cached like RAM, the PAT is left at
+            // its defaults so there is no write-combining type to pick
+            LimineMemoryMapEntryType::Framebuffer => MMIO_FLAGS,
+
+            _ => HHDM_FLAGS,
+        };
+
+        let start = PAddr(entry.base).align_down_to_base_page();
+        let end = PAddr(entry.base + entry.len).align_up_to_base_page();
+
+        trace!(
+            "Mapping {:?} {:#x} - {:#x} into the higher half direct map",
+            entry.typ,
+            start,
+            end
+        );
+
+        map_hhdm_range(mapper, start, end, flags);
+    }
+}
+
+unsafe fn map_framebuffers(mapper: &mut X64MemoryMapper) {
+    let Some(response) = FRAMEBUFFER_REQUEST.get_response().get() else {
+        return;
+    };
+
+    for framebuffer in response.framebuffers() {
+        let Some(address) = framebuffer.address.as_ptr() else {
+            continue;
+        };
+
+        let start = PAddr::from(VirtualAddress::from(address).to_physical());
+        let end = (start + framebuffer.size()).align_up_to_base_page();
+
+        trace!(
+            "Mapping framebuffer {}x{} {:#x} - {:#x}",
+            framebuffer.width,
+            framebuffer.height,
+            start,
+            end
+        );
+
+        // The memory map normally lists the framebuffer, in which case
+        // `map_hhdm` has mapped it already
+        let start = start.align_down_to_base_page().as_u64();
+        for page in (start..end.as_u64()).step_by(BASE_PAGE_SIZE) {
+            let page = PhysicalAddress::new(page);
+            if mapper.translate(page.to_virtual()).is_none() {
+                mapper.map(page, page.to_virtual(), PageSize::Size4KiB, MMIO_FLAGS);
+            }
         }
     }
+}
+
+/// Maps a range of physical memory into the higher half direct map, using the
+/// largest pages possible.
+unsafe fn map_hhdm_range(mapper: &mut X64MemoryMapper, start: PAddr, end: PAddr, flags: PageFlags) {
+    let huge_pages = supports_huge_pages();
 
-    unsafe fn alloc_pdpt(&mut self, pml4_idx: usize, flags: u64) -> *mut PDPT {
-        let frame = allocate_pages(1).as_u64();
-        let pdpt = &mut self.pml4[pml4_idx];
+    let mut phys = start.as_u64();
+    while phys < end.as_u64() {
+        let remaining = end.as_u64() - phys;
 
-        pdpt.0 = frame | flags;
+        let size = [PageSize::Size1GiB, PageSize::Size2MiB]
+            .into_iter()
+            .filter(|&size| size != PageSize::Size1GiB || huge_pages)
+            .find(|size| phys % size.bytes() == 0 && remaining >= size.bytes())
+            .unwrap_or(PageSize::Size4KiB);
 
-        trace!("Allocated PDPT at {:#x}", frame);
+        let addr = PhysicalAddress::new(phys);
+        mapper.map(addr, addr.to_virtual(), size, flags);
 
-        frame as *mut PDPT
+        phys += size.bytes();
     }
+}
+
+/// The physical address bits of a page table entry pointing to a table or a 4 KiB page.
+const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
 
-    unsafe fn alloc_pd(&mut self, pml4_idx: usize, pdp_idx: usize, flags: u64) -> *mut PD {
-        let frame = allocate_pages(1).as_u64();
-        let pdpt = self.get_pdpt(pml4_idx).unwrap();
+/// The flags of a page table entry that are shared by every paging level.
+const FLAGS_MASK: u64 = PTFlags::RW.bits()
+    | PTFlags::US.bits()
+    | PTFlags::PWT.bits()
+    | PTFlags::PCD.bits()
+    | PTFlags::G.bits()
+    | PTFlags::XD.bits();
 
-        let pdpt = &mut *pdpt;
-        let pdp = &mut pdpt[pdp_idx];
+/// The page size bit of a PDPT or PD entry.
+const HUGE_PAGE: u64 = PDFlags::PS.bits();
 
-        pdp.0 = frame | flags;
+/// Converts `PageFlags` into the bits of a present leaf entry.
+fn entry_bits(flags: PageFlags) -> u64 {
+    let mut bits = PTFlags::P;
 
-        trace!("Allocated PD at {:#x}", frame);
+    bits.set(PTFlags::RW, flags.contains(PageFlags::WRITABLE));
+    bits.set(PTFlags::US, flags.contains(PageFlags::USER));
+    bits.set(PTFlags::XD, flags.contains(PageFlags::NO_EXECUTE));
+    bits.set(PTFlags::G, flags.contains(PageFlags::GLOBAL));
+    bits.set(PTFlags::PWT, flags.contains(PageFlags::WRITE_THROUGH));
+    bits.set(PTFlags::PCD, flags.contains(PageFlags::CACHE_DISABLE));
+
+    bits.bits()
+}
 
-        frame as *mut PD
+/// Converts the bits of a leaf entry back into `PageFlags`.
+fn page_flags(bits: u64) -> PageFlags {
+    let bits = PTFlags::from_bits_truncate(bits);
+    let mut flags = PageFlags::empty();
+
+    flags.set(PageFlags::WRITABLE, bits.contains(PTFlags::RW));
+    flags.set(PageFlags::USER, bits.contains(PTFlags::US));
+    flags.set(PageFlags::NO_EXECUTE, bits.contains(PTFlags::XD));
+    flags.set(PageFlags::GLOBAL, bits.contains(PTFlags::G));
+    flags.set(PageFlags::WRITE_THROUGH, bits.contains(PTFlags::PWT));
+    flags.set(PageFlags::CACHE_DISABLE, bits.contains(PTFlags::PCD));
+
+    flags
+}
+
+/// Returns the physical address a leaf entry of the given size points to.
+fn leaf_address(entry: u64, size: PageSize) -> u64 {
+    entry & ADDRESS_MASK & !(size.bytes() - 1)
+}
+
+/// Returns whether the CPU supports 1 GiB pages.
+pub fn supports_huge_pages() -> bool {
+    // CPUID.80000001H:EDX.Page1GB [bit 26]
+    let cpuid = unsafe { __cpuid(0x8000_0001) };
+    cpuid.edx & (1 << 26) != 0
+}
+
+/// The indices into each paging level of a virtual address.
+struct TableIndices {
+    pml4: usize,
+    pdpt: usize,
+    pd: usize,
+    pt: usize,
+}
+
+impl TableIndices {
+    fn new(virt: VirtualAddress) -> Self {
+        let virt = VAddr::from(virt.as_u64());
+
+        Self {
+            pml4: pml4_index(virt),
+            pdpt: pdpt_index(virt),
+            pd: pd_index(virt),
+            pt: pt_index(virt),
+        }
     }
+}
 
-    unsafe fn alloc_pt(
-        &mut self,
-        pml4_idx: usize,
-        pdp_idx: usize,
-        pd_idx: usize,
-        flags: u64,
-    ) -> *mut PT {
-        let frame = allocate_pages(1).as_u64();
-        let pd = self.get_pd(pml4_idx, pdp_idx).unwrap();
+/// Pages other CPUs may still hold TLB entries for after `unmap` or `protect`.
+///
+/// They have to be shot down with `shoot_down` once the page tables are
+/// unlocked, waiting for other CPUs while holding the lock could deadlock.
+///
+#[must_use]
+pub struct StaleEntries {
+    /// The ids of the CPUs that may cache the pages.
+    targets: u64,
+    pages: Vec<VirtualAddress>,
+}
+
+impl StaleEntries {
+    const fn new() -> Self {
+        Self {
+            targets: 0,
+            pages: Vec::new(),
+        }
+    }
+
+    /// Invalidates the pages on every CPU that may cache them.
+    pub fn shoot_down(mut self) {
+        let pages = core::mem::take(&mut self.pages);
+        shootdown::shootdown(self.targets, &pages);
+    }
+}
+
+impl Drop for StaleEntries {
+    fn drop(&mut self) {
+        debug_assert!(
+            self.pages.is_empty(),
+            "Stale TLB entries were dropped without a shootdown"
+        );
+    }
+}
+
+pub struct X64MemoryMapper {
+    pml4: &'static mut PML4,
+    stale: StaleEntries,
+}
+
+impl X64MemoryMapper {
+    /// Returns the physical address of the PML4.
+    pub fn pml4_address(&self) -> PhysicalAddress {
+        VirtualAddress::from(self.pml4 as *const PML4).to_physical()
+    }
+
+    /// Returns the pages `unmap` and `protect` changed that other CPUs may
+    /// still have cached, see `StaleEntries`.
+    pub fn take_stale(&mut self) -> StaleEntries {
+        core::mem::replace(&mut self.stale, StaleEntries::new())
+    }
+
+    /// Flushes `virt` from the local TLB and remembers it for a shootdown if
+    /// other CPUs may use these page tables.
+    unsafe fn invalidate(&mut self, virt: VirtualAddress) {
+        tlb::flush(virt.as_u64() as usize);
+
+        // Nothing to do while the APs are not running, this also covers the
+        // early boot where the per-CPU data isn't set up yet
+        let online = smp::online_mask();
+        if online.count_ones() <= 1 {
+            return;
+        }
 
-        let pd = &mut *pd;
-        let pt = &mut pd[pd_idx];
+        // The higher half is shared by every address space, the lower half
+        // only matters to the CPUs running on these tables
+        let targets = if KERNEL_PML4_ENTRIES.contains(&TableIndices::new(virt).pml4) {
+            online
+        } else {
+            let pml4 = self.pml4_address();
+
+            (0..MAX_CPUS)
+                .filter(|id| online & (1 << id) != 0)
+                .filter(|&id| percpu::cpu(id).map_or(false, |cpu| cpu.pml4() == pml4))
+                .fold(0, |targets, id| targets | 1 << id)
+        };
 
-        pt.0 = frame | flags;
+        if targets != 0 {
+            self.stale.targets |= targets;
+            self.stale.pages.push(virt);
+        }
+    }
 
-        trace!("Allocated PT at {:#x}", frame);
+    /// Returns the next level table an entry points to.
+    ///
+    /// Returns `None` if the entry is not present or maps a huge page.
+    unsafe fn next_table<T>(entry: u64) -> Option<*mut T> {
+        if entry & PTFlags::P.bits() == 0 || entry & HUGE_PAGE != 0 {
+            return None;
+        }
 
-        frame as *mut PT
+        Some(table_ptr(PAddr(entry & ADDRESS_MASK)))
+    }
+
+    /// Returns the next level table an entry points to, allocating it if missing.
+    unsafe fn next_table_or_create<T>(entry: &mut u64, user: bool) -> *mut T {
+        if *entry & PTFlags::P.bits() != 0 {
+            assert!(
+                *entry & HUGE_PAGE == 0,
+                "Cannot map a page inside of an existing huge page"
+            );
+
+            // Intermediate tables are as permissive as possible, the leaf entry
+            // decides about the actual permissions
+            if user {
+                *entry |= PTFlags::US.bits();
+            }
+
+            return table_ptr(PAddr(*entry & ADDRESS_MASK));
+        }
+
+        let frame = allocate_pages(1);
+        let table = table_ptr::<PT>(frame);
+        table.write_bytes(0, 1);
+
+        *entry = frame.as_u64() | (PTFlags::P | PTFlags::RW).bits();
+        if user {
+            *entry |= PTFlags::US.bits();
+        }
+
+        trace!("Allocated page table at {:#x}", frame);
+
+        table as *mut T
+    }
+
+    /// Returns the leaf entry mapping `virt` together with the size of its page.
+    unsafe fn leaf_entry(&self, virt: VirtualAddress) -> Option<(*mut u64, PageSize)> {
+        let idx = TableIndices::new(virt);
+
+        let pdpt = Self::next_table::<PDPT>(self.pml4[idx.pml4].0)?;
+        let pdpt_entry = &mut (*pdpt)[idx.pdpt].0;
+        if *pdpt_entry & PTFlags::P.bits() == 0 {
+            return None;
+        }
+        if *pdpt_entry & HUGE_PAGE != 0 {
+            return Some((pdpt_entry, PageSize::Size1GiB));
+        }
+
+        let pd = Self::next_table::<PD>(*pdpt_entry)?;
+        let pd_entry = &mut (*pd)[idx.pd].0;
+        if *pd_entry & PTFlags::P.bits() == 0 {
+            return None;
+        }
+        if *pd_entry & HUGE_PAGE != 0 {
+            return Some((pd_entry, PageSize::Size2MiB));
+        }
+
+        let pt = Self::next_table::<PT>(*pd_entry)?;
+        let pt_entry = &mut (*pt)[idx.pt].0;
+        if *pt_entry & PTFlags::P.bits() == 0 {
+            return None;
+        }
+
+        Some((pt_entry, PageSize::Size4KiB))
     }
 
     // Debugging
@@ -112,11 +865,9 @@ impl X64MemoryMapper {
         trace!("PML4:");
 
         for (pml_idx, pml4_entry) in self.pml4.iter().enumerate() {
-            if !pml4_entry.is_present() {
+            let Some(pdpt) = (unsafe { Self::next_table::<PDPT>(pml4_entry.0) }) else {
                 continue;
-            }
-
-            let pdpt = unsafe { pml4_entry.address().0 as *const PDPT };
+            };
 
             trace!("  PDPT {:#x}:", pml_idx);
             for (pdp_idx, pdpt_entry) in unsafe { &*pdpt }.iter().enumerate() {
@@ -124,7 +875,13 @@ impl X64MemoryMapper {
                     continue;
                 }
 
-                let pd = unsafe { pdpt_entry.address().0 as *const PD };
+                let virt = (pml_idx as u64) << 39 | (pdp_idx as u64) << 30;
+
+                let Some(pd) = (unsafe { Self::next_table::<PD>(pdpt_entry.0) }) else {
+                    let phys = leaf_address(pdpt_entry.0, PageSize::Size1GiB);
+                    trace!("    {:#x} -> {:#x} (1 GiB)", virt, phys);
+                    continue;
+                };
 
                 trace!("    PD {:#x}:", pdp_idx);
                 for (pd_idx, pd_entry) in unsafe { &*pd }.iter().enumerate() {
@@ -132,7 +889,13 @@ impl X64MemoryMapper {
                         continue;
                     }
 
-                    let pt = unsafe { pd_entry.address().0 as *const PT };
+                    let virt = virt | (pd_idx as u64) << 21;
+
+                    let Some(pt) = (unsafe { Self::next_table::<PT>(pd_entry.0) }) else {
+                        let phys = leaf_address(pd_entry.0, PageSize::Size2MiB);
+                        trace!("      {:#x} -> {:#x} (2 MiB)", virt, phys);
+                        continue;
+                    };
 
                     trace!("      PT {:#x}:", pd_idx);
                     for (pt_idx, pt_entry) in unsafe { &*pt }.iter().enumerate() {
@@ -141,12 +904,7 @@ impl X64MemoryMapper {
                         }
 
                         let phys = pt_entry.address().0;
-                        let virt = VAddr::from_u64(
-                            (pml_idx as u64) << 39
-                                | (pdp_idx as u64) << 30
-                                | (pd_idx as u64) << 21
-                                | (pt_idx as u64) << 12,
-                        );
+                        let virt = virt | (pt_idx as u64) << 12;
 
                         trace!("        {:#x} -> {:#x}", virt, phys);
                     }
@@ -158,92 +916,143 @@ impl X64MemoryMapper {
 
 impl MemoryMapper for X64MemoryMapper {
     unsafe fn new() -> Self {
-        let pml4 = allocate_pages(1);
+        let pml4 = table_ptr::<PML4>(allocate_pages(1));
+        pml4.write_bytes(0, 1);
 
         X64MemoryMapper {
-            pml4: &mut *(pml4.as_u64() as *mut PML4),
+            pml4: &mut *pml4,
+            stale: StaleEntries::new(),
         }
     }
 
     unsafe fn from_active() -> Self {
-        let pml4 = cr3() as *mut PML4;
-        X64MemoryMapper { pml4: &mut *pml4 }
+        let pml4 = table_ptr::<PML4>(PAddr(cr3() & ADDRESS_MASK));
+        X64MemoryMapper {
+            pml4: &mut *pml4,
+            stale: StaleEntries::new(),
+        }
     }
 
     unsafe fn map(
         &mut self,
-        phys: crate::arch::PhysicalAddress,
-        virt: crate::arch::VirtualAddress,
-        flags: u64,
+        phys: PhysicalAddress,
+        virt: VirtualAddress,
+        size: PageSize,
+        flags: PageFlags,
     ) {
-        let pml_idx: usize = (virt.0 >> 39 & 0o777u64) as _; // 9 bits
-        let pdp_idx: usize = (virt.0 >> 30 & 0o777u64) as _; // 9 bits
-        let pd_idx: usize = (virt.0 >> 21 & 0o777u64) as _; // 9 bits
-        let pt_idx: usize = (virt.0 >> 12 & 0o777u64) as _; // 9 bits
-
-        trace!(
-            "Mapping {:#x} -> {:#x} (pml_idx: {:#x}, pdp_idx: {:#x}, pd_idx: {:#x}, pt_idx: {:#x})",
-            virt.0,
-            phys.0,
-            pml_idx,
-            pdp_idx,
-            pd_idx,
-            pt_idx
+        assert!(
+            phys.as_u64() % size.bytes() == 0 && virt.as_u64() % size.bytes() == 0,
+            "Mapping {:#x} -> {:#x} is not aligned to {:?}",
+            virt.as_u64(),
+            phys.as_u64(),
+            size
         );
 
-        let pt = match self.get_pt(pml_idx, pdp_idx, pd_idx) {
-            Some(pt) => pt,
-            None => {
-                let pt = self.alloc_pt(pml_idx, pdp_idx, pd_idx, flags);
-                let pt = &mut *pt;
+        let idx = TableIndices::new(virt);
+        let user = flags.contains(PageFlags::USER);
 
-                for entry in pt.iter_mut() {
-                    entry.0 = 0;
-                }
+        let pdpt = Self::next_table_or_create::<PDPT>(&mut self.pml4[idx.pml4].0, user);
+        let pdpt_entry = &mut (*pdpt)[idx.pdpt].0;
+
+        let entry = if size == PageSize::Size1GiB {
+            pdpt_entry
+        } else {
+            let pd = Self::next_table_or_create::<PD>(pdpt_entry, user);
+            let pd_entry = &mut (*pd)[idx.pd].0;
 
-                pt
+            if size == PageSize::Size2MiB {
+                pd_entry
+            } else {
+                let pt = Self::next_table_or_create::<PT>(pd_entry, user);
+                &mut (*pt)[idx.pt].0
             }
         };
 
-        let pt = &mut *pt;
-        let pt_entry = &mut pt[pt_idx];
+        if size != PageSize::Size4KiB {
+            assert!(
+                *entry & PTFlags::P.bits() == 0 || *entry & HUGE_PAGE != 0,
+                "Cannot replace the page table at {:#x} with a huge page",
+                virt.as_u64()
+            );
+        }
 
-        pt_entry.0 = phys.0 | flags;
+        *entry = phys.as_u64() | entry_bits(flags);
+        if size != PageSize::Size4KiB {
+            *entry |= HUGE_PAGE;
+        }
+
+        tlb::flush(virt.as_u64() as usize);
     }
 
-    unsafe fn unmap(&mut self, virt: crate::arch::VirtualAddress) {
-        let pml_idx: usize = (virt.0 >> 39 & 0o777u64) as _; // 9 bits
-        let pdp_idx: usize = (virt.0 >> 30 & 0o777u64) as _; // 9 bits
-        let pd_idx: usize = (virt.0 >> 21 & 0o777u64) as _; // 9 bits
-        let pt_idx: usize = (virt.0 >> 12 & 0o777u64) as _; // 9 bits
+    unsafe fn unmap(&mut self, virt: VirtualAddress) -> Option<(PhysicalAddress, PageSize)> {
+        let (entry, size) = self.leaf_entry(virt)?;
+        let phys = PhysicalAddress::new(leaf_address(*entry, size));
 
         trace!(
-            "Unmapping {:#x} (pml_idx: {:#x}, pdp_idx: {:#x}, pd_idx: {:#x}, pt_idx: {:#x})",
-            virt.0,
-            pml_idx,
-            pdp_idx,
-            pd_idx,
-            pt_idx
+            "Unmapping {:#x} -> {:#x} ({:?})",
+            virt.as_u64(),
+            phys.as_u64(),
+            size
         );
 
-        let pt = match self.get_pt(pml_idx, pdp_idx, pd_idx) {
-            Some(pt) => pt,
-            None => return,
+        *entry = 0;
+        self.invalidate(virt);
+
+        Some((phys, size))
+    }
+
+    fn translate(&self, virt: VirtualAddress) -> Option<Translation> {
+        let (entry, size) = unsafe { self.leaf_entry(virt)? };
+        let entry = unsafe { *entry };
+
+        let offset = virt.as_u64() & (size.bytes() - 1);
+
+        Some(Translation {
+            phys: PhysicalAddress::new(leaf_address(entry, size) + offset),
+            size,
+            flags: page_flags(entry),
+        })
+    }
+
+    unsafe fn protect(&mut self, virt: VirtualAddress, flags: PageFlags) -> bool {
+        let Some((entry, _)) = self.leaf_entry(virt) else {
+            return false;
         };
 
-        let pt = &mut *pt;
-        let pt_entry = &mut pt[pt_idx];
+        let old_flags = page_flags(*entry);
+
+        *entry = (*entry & !FLAGS_MASK) | entry_bits(flags);
+
+        // Other CPUs pick up new permissions on their own, but may keep using
+        // stale entries that allow more than the page does now
+        let removed = old_flags.difference(flags);
+        let added = flags.difference(old_flags);
 
-        pt_entry.0 = 0;
+        if !removed.is_empty()
+            || added.intersects(
+                PageFlags::NO_EXECUTE | PageFlags::WRITE_THROUGH | PageFlags::CACHE_DISABLE,
+            )
+        {
+            self.invalidate(virt);
+        } else {
+            tlb::flush(virt.as_u64() as usize);
+        }
+
+        true
     }
 
     unsafe fn submit(&mut self) {
-        let tst = self.pml4 as *const PML4;
-        trace!("Submitting PML4 {:#x}", tst as u64);
-        let tst2 = cr3();
-        trace!("Submitting PML4 {:#x}", tst2);
+        let pml4 = self.pml4_address();
+        trace!(
+            "Submitting PML4 {:#x} (active: {:#x})",
+            pml4.as_u64(),
+            cr3()
+        );
+
+        cr3_write(pml4.as_u64());
 
-        let pml4 = self.pml4 as *mut PML4;
-        cr3_write(pml4 as u64);
+        if let Some(cpu) = percpu::try_this_cpu() {
+            cpu.set_pml4(pml4);
+        }
     }
 }
@@ -1,23 +1,40 @@
 use core::arch::asm;
 
 use alloc::{boxed::Box, vec::Vec};
-use limine::{LimineSmpInfo, LimineSmpRequest};
 use spin::Mutex;
 
-use crate::allocator;
+use x86::current::paging::BASE_PAGE_SIZE;
+
+use crate::{allocator, arch::PhysicalAddress};
 
 pub mod acpi;
+pub mod clock;
+pub mod context;
+pub mod exceptions;
 pub mod gdt;
+pub mod hpet;
 pub mod idt;
+pub mod interrupts;
+pub mod ioapic;
+pub mod ipi;
+pub mod irq;
+pub mod lapic;
 pub mod mmu;
+pub mod modules;
+pub mod page_fault;
+pub mod percpu;
+pub mod pic;
+pub mod pit;
+pub mod power;
+pub mod rtc;
+pub mod shootdown;
+pub mod smp;
 
 unsafe fn common_startup() {
-    idt::disable();
+    let _interrupts = idt::InterruptGuard::new();
 
     gdt::init();
     idt::init();
-
-    idt::enable();
 }
 
 #[no_mangle]
@@ -31,18 +48,94 @@ extern "C" fn bsp_start() -> ! {
     allocator::init();
     mmu::init();
 
-    info!("CPU - 0 (BSP) started");
+    unsafe {
+        lapic::init();
+        percpu::init(0, gdt::bsp_tables());
+
+        // Limine hands over with interrupts disabled, the handlers need the
+        // per-CPU data before they can be turned on
+        idt::enable();
+
+        shootdown::init();
+        acpi::init();
+        modules::init();
+
+        hpet::init();
+        clock::init();
+
+        rtc::init();
+
+        ioapic::init();
+        power::init();
+        smp::init();
+    }
+
+    // Limine's stack lives in bootloader reclaimable memory, so we have to
+    // move off of it before that memory can be handed to the frame allocator
+    let stack_top = allocate_stack(BOOT_STACK_PAGES);
+
+    unsafe { switch_stack(stack_top, bsp_start_on_kernel_stack) }
+}
+
+extern "C" fn bsp_start_on_kernel_stack() -> ! {
+    // NOTE: Every limine response lives in bootloader reclaimable memory,
+    //       anything that still needs one has to run before this point.
+    //
+    // A CPU that didn't come online in time may still wake up on its
+    // bootloader provided stack and page tables
+    if smp::all_cpus_online() {
+        unsafe {
+            allocator::reclaim_bootloader_memory();
+        }
+    } else {
+        error!("Not reclaiming bootloader memory, some CPUs never came online");
+    }
+
+    info!("CPU - {} (BSP) started", percpu::this_cpu().id);
 
     crate::bsp_main();
 }
 
-#[no_mangle]
-extern "C" fn ap_start(info: *const LimineSmpInfo) -> ! {
-    let info = unsafe { &*info };
+/// The size of the stack every CPU continues booting on.
+const BOOT_STACK_PAGES: usize = 16;
+
+/// Allocates a kernel stack and returns its top.
+///
+/// # Arguments
+/// * `pages` - The size of the stack in pages.
+///
+fn allocate_stack(pages: usize) -> u64 {
+    let stack = unsafe { allocator::allocate_pages(pages) };
 
-    info!("CPU - {} (AP) started", info.processor_id);
+    PhysicalAddress::from(stack).to_virtual().as_u64() + (pages * BASE_PAGE_SIZE) as u64
+}
+
+/// Switches to the given stack and calls `entry` on it.
+///
+/// # Safety
+///
+/// `stack_top` must point to the (16 byte aligned) end of a valid stack,
+/// nothing on the current stack may be used afterwards.
+///
+unsafe fn switch_stack(stack_top: u64, entry: extern "C" fn() -> !) -> ! {
+    asm!(
+        "mov rsp, {stack_top}",
+        "xor ebp, ebp",
+        "call {entry}",
+        "ud2",
+        stack_top = in(reg) stack_top,
+        entry = in(reg) entry,
+        options(noreturn)
+    );
+}
 
-    crate::ap_main();
+/// Enables interrupts and halts until the next one arrives.
+///
+/// An interrupt that became pending while interrupts were disabled still
+/// ends the halt, since `sti` only takes effect after the next instruction.
+///
+pub fn enable_and_halt() {
+    unsafe { asm!("sti; hlt", options(nomem, nostack)) };
 }
 
 pub fn hcf() -> ! {
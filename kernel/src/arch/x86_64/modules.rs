@@ -0,0 +1,91 @@
+use alloc::{string::String, vec::Vec};
+use limine::{LimineFile, LimineModuleRequest};
+use spin::Once;
+
+static MODULE_REQUEST: LimineModuleRequest = LimineModuleRequest::new(0);
+
+/// A file the bootloader loaded next to the kernel (a `MODULE_PATH` in `limine.cfg`).
+pub struct BootModule {
+    /// The path of the file on the boot volume, with a leading slash.
+    pub path: String,
+
+    /// The `MODULE_CMDLINE` of the module, the kernel looks modules up by it.
+    pub cmdline: String,
+
+    data: &'static [u8],
+}
+
+impl BootModule {
+    /// Returns the contents of the file.
+    pub fn data(&self) -> &'static [u8] {
+        self.data
+    }
+}
+
+static MODULES: Once<Vec<BootModule>> = Once::new();
+
+/// Collects the modules the bootloader loaded.
+///
+/// The file descriptions live in bootloader reclaimable memory, so this has to
+/// be called before that memory is reclaimed. The file contents live in
+/// kernel and modules memory, which is never handed to the frame allocator
+/// and stays mapped in the higher half direct map.
+///
+pub unsafe fn init() {
+    let modules = MODULE_REQUEST
+        .get_response()
+        .get()
+        .map_or(&[][..], |response| response.modules());
+
+    let modules = MODULES.call_once(|| {
+        modules
+            .iter()
+            .map(|module| collect(module))
+            .collect::<Vec<_>>()
+    });
+
+    for module in modules {
+        info!(
+            "Module {} ({:?}), {} KiB",
+            module.path,
+            module.cmdline,
+            module.data.len() / 1024
+        );
+    }
+}
+
+unsafe fn collect(file: &LimineFile) -> BootModule {
+    let path = file
+        .path
+        .to_str()
+        .map_or(String::new(), |path| path.to_string_lossy().into_owned());
+
+    let cmdline = file.cmdline.to_str().map_or(String::new(), |cmdline| {
+        cmdline.to_string_lossy().into_owned()
+    });
+
+    let data = match file.base.as_ptr() {
+        Some(base) => core::slice::from_raw_parts(base, file.length as usize),
+        None => &[],
+    };
+
+    BootModule {
+        path,
+        cmdline,
+        data,
+    }
+}
+
+/// Returns every module the bootloader loaded.
+pub fn modules() -> &'static [BootModule] {
+    MODULES.get().map_or(&[], Vec::as_slice)
+}
+
+/// Returns the module with the given command line.
+///
+/// # Arguments
+/// * `cmdline` - The `MODULE_CMDLINE` the module was given in `limine.cfg`.
+///
+pub fn find(cmdline: &str) -> Option<&'static BootModule> {
+    modules().iter().find(|module| module.cmdline == cmdline)
+}
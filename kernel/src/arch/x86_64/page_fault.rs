@@ -0,0 +1,120 @@
+use core::fmt;
+
+use log::Level;
+use x86::{controlregs::cr2, irq::PageFaultError};
+
+use super::{
+    exceptions,
+    idt::ExceptionStackFrame,
+    interrupts::InterruptContext,
+    mmu::{self, DemandFault},
+};
+use crate::arch::{HalMemoryMapper, MemoryMapper, Translation, VirtualAddress};
+
+/// Handles page faults (vector 14).
+///
+/// Accesses to not present pages of a demand paged region are resolved by
+/// mapping a fresh frame, every other page fault is fatal.
+///
+pub(super) fn handle(context: &mut InterruptContext) {
+    let address = VirtualAddress::new(unsafe { cr2() } as u64);
+    let error = PageFaultError::from_bits_truncate(context.error_code as u32);
+
+    let demand_fault = if error.intersects(PageFaultError::P | PageFaultError::RSVD) {
+        DemandFault::NotDemandPaged
+    } else {
+        unsafe { mmu::handle_demand_fault(address) }
+    };
+
+    if demand_fault == DemandFault::Mapped {
+        return;
+    }
+
+    exceptions::log_registers(Level::Error, context);
+
+    let mapper = unsafe { HalMemoryMapper::from_active() };
+
+    panic!(
+        "{}",
+        PageFaultReport {
+            address,
+            error,
+            out_of_memory: demand_fault == DemandFault::OutOfMemory,
+            translation: mapper.translate(address),
+            frame: &context.frame,
+        }
+    );
+}
+
+/// A human readable description of a fatal page fault.
+struct PageFaultReport<'a> {
+    address: VirtualAddress,
+    error: PageFaultError,
+    /// The page is demand paged, but there was no frame left to back it.
+    out_of_memory: bool,
+    translation: Option<Translation>,
+    frame: &'a ExceptionStackFrame,
+}
+
+impl fmt::Display for PageFaultReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = if self.error.contains(PageFaultError::ID) {
+            "instruction fetch"
+        } else if self.error.contains(PageFaultError::WR) {
+            "write"
+        } else {
+            "read"
+        };
+
+        let mode = if self.error.contains(PageFaultError::US) {
+            "user"
+        } else {
+            "kernel"
+        };
+
+        let cause = if self.error.contains(PageFaultError::RSVD) {
+            "reserved bit set in a paging structure"
+        } else if self.error.contains(PageFaultError::PK) {
+            "protection key violation"
+        } else if self.error.contains(PageFaultError::P) {
+            "protection violation"
+        } else if self.out_of_memory {
+            "out of memory while demand paging"
+        } else {
+            "page not present"
+        };
+
+        writeln!(
+            f,
+            "Page fault: {} {} of {:#x} ({})",
+            mode,
+            access,
+            self.address.as_u64(),
+            cause
+        )?;
+
+        match self.translation {
+            Some(translation) => writeln!(
+                f,
+                "  mapping:  {:#x} ({:?}, {:?})",
+                translation.phys.as_u64(),
+                translation.size,
+                translation.flags
+            )?,
+            None => writeln!(f, "  mapping:  <not mapped>")?,
+        }
+
+        writeln!(f, "  error:    {:#x}", self.error.bits())?;
+        writeln!(
+            f,
+            "  rip:      {:#x} (cs: {:#x})",
+            self.frame.instruction_pointer, self.frame.code_segment
+        )?;
+        writeln!(
+            f,
+            "  rsp:      {:#x} (ss: {:#x})",
+            self.frame.stack_pointer, self.frame.stack_segment
+        )?;
+        write!(f, "  rflags:   {:#x}", self.frame.cpu_flags)
+    }
+}
@@ -0,0 +1,135 @@
+use core::{
+    arch::asm,
+    ptr::{self, addr_of_mut},
+    sync::atomic::{AtomicPtr, AtomicU64, Ordering},
+};
+
+use alloc::boxed::Box;
+use x86::msr::{rdmsr, wrmsr, IA32_GS_BASE, IA32_KERNEL_GSBASE};
+
+use super::{gdt::CpuTables, lapic, mmu, smp::MAX_CPUS};
+use crate::{arch::PhysicalAddress, task::scheduler::CpuScheduler};
+
+/// The state every CPU keeps for itself.
+///
+/// While running in the kernel `IA32_GS_BASE` points to the structure of the
+/// current CPU, the entry stubs `swapgs` it into `IA32_KERNEL_GSBASE` while
+/// ring 3 runs with its own GS base.
+///
+#[repr(C)]
+pub struct PerCpu {
+    /// Points to the structure itself, so it can be found with a single `gs` relative load.
+    this: *const PerCpu,
+
+    /// Scratch space for entry code that has no usable stack yet (the user
+    /// stack pointer while switching to the kernel stack).
+    pub scratch: u64,
+
+    /// The kernel assigned id of the CPU, the BSP is 0 and the APs are
+    /// numbered in the order they come online.
+    pub id: usize,
+    pub lapic_id: u32,
+
+    tables: *mut CpuTables,
+
+    /// The physical address of the PML4 loaded in CR3, other CPUs read it
+    /// to decide whether a TLB shootdown concerns this CPU.
+    pml4: AtomicU64,
+
+    pub scheduler: CpuScheduler,
+}
+
+// The tables and the scheduler cells are only ever touched by the CPU owning the
+// structure, the run queue is behind a lock
+unsafe impl Sync for PerCpu {}
+
+impl PerCpu {
+    /// Sets the stack the CPU switches to when an interrupt arrives in ring 3.
+    ///
+    /// # Arguments
+    /// * `stack_top` - The (16 byte aligned) end of the stack.
+    ///
+    pub fn set_kernel_stack(&self, stack_top: u64) {
+        // SAFETY: The TSS is only touched by the CPU it belongs to
+        unsafe { (*self.tables).set_kernel_stack(stack_top) };
+    }
+
+    /// Returns the physical address of the PML4 the CPU runs on.
+    pub fn pml4(&self) -> PhysicalAddress {
+        PhysicalAddress::new(self.pml4.load(Ordering::Acquire))
+    }
+
+    /// Records the PML4 the CPU just loaded into CR3.
+    pub(super) fn set_pml4(&self, pml4: PhysicalAddress) {
+        self.pml4.store(pml4.as_u64(), Ordering::Release);
+    }
+}
+
+/// The per-CPU structures of every CPU, indexed by their id.
+static CPUS: [AtomicPtr<PerCpu>; MAX_CPUS] = [CPU_INIT; MAX_CPUS];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const CPU_INIT: AtomicPtr<PerCpu> = AtomicPtr::new(ptr::null_mut());
+
+/// Allocates the per-CPU structure of the calling CPU and installs it as its GS base.
+///
+/// # Arguments
+/// * `id` - The kernel assigned id of the CPU.
+/// * `tables` - The GDT and TSS loaded on the calling CPU.
+///
+/// # Safety
+///
+/// Has to be called once per CPU after its GDT is loaded, since loading the
+/// GS selector clears the GS base.
+///
+pub unsafe fn init(id: usize, tables: &'static mut CpuTables) {
+    let per_cpu = Box::leak(Box::new(PerCpu {
+        this: ptr::null(),
+        scratch: 0,
+        id,
+        lapic_id: lapic::id(),
+        tables,
+        pml4: AtomicU64::new(mmu::active_pml4().as_u64()),
+        scheduler: CpuScheduler::new(),
+    }));
+
+    per_cpu.this = addr_of_mut!(*per_cpu);
+
+    CPUS[id].store(per_cpu, Ordering::Release);
+
+    wrmsr(IA32_GS_BASE, per_cpu.this as u64);
+    wrmsr(IA32_KERNEL_GSBASE, 0);
+}
+
+/// Returns the per-CPU structure of the CPU with the given id.
+///
+/// Returns `None` if that CPU didn't set up its structure yet.
+///
+pub fn cpu(id: usize) -> Option<&'static PerCpu> {
+    let per_cpu = CPUS.get(id)?.load(Ordering::Acquire);
+    unsafe { per_cpu.as_ref() }
+}
+
+/// Returns the per-CPU structure of the calling CPU, or `None` if it didn't
+/// set up its structure yet.
+///
+pub fn try_this_cpu() -> Option<&'static PerCpu> {
+    if unsafe { rdmsr(IA32_GS_BASE) } == 0 {
+        return None;
+    }
+
+    Some(this_cpu())
+}
+
+/// Returns the per-CPU structure of the calling CPU.
+///
+/// Every CPU installs its structure before reaching `bsp_main` or `ap_main`.
+///
+pub fn this_cpu() -> &'static PerCpu {
+    unsafe {
+        let this: *const PerCpu;
+        asm!("mov {}, gs:[0]", out(reg) this, options(nostack, preserves_flags, readonly));
+
+        &*this
+    }
+}
@@ -0,0 +1,42 @@
+use x86::io::outb;
+
+const MASTER_COMMAND: u16 = 0x20;
+const MASTER_DATA: u16 = 0x21;
+const SLAVE_COMMAND: u16 = 0xA0;
+const SLAVE_DATA: u16 = 0xA1;
+
+const ICW1_INIT: u8 = 0x10;
+const ICW1_ICW4: u8 = 0x01;
+const ICW4_8086: u8 = 0x01;
+
+/// The vector the legacy PIC delivers IRQ 0 at after being remapped.
+pub const VECTOR_BASE: u8 = 0x20;
+
+/// The vectors spurious interrupts of the master and the slave PIC arrive at.
+pub const SPURIOUS_VECTORS: [u8; 2] = [VECTOR_BASE + 7, VECTOR_BASE + 15];
+
+/// Disables the legacy 8259 PIC in favour of the APIC.
+///
+/// The PIC is remapped away from the exception vectors before masking every
+/// line, a masked PIC can still raise spurious interrupts and those must not
+/// be mistaken for exceptions.
+///
+pub unsafe fn disable() {
+    outb(MASTER_COMMAND, ICW1_INIT | ICW1_ICW4);
+    outb(SLAVE_COMMAND, ICW1_INIT | ICW1_ICW4);
+
+    outb(MASTER_DATA, VECTOR_BASE);
+    outb(SLAVE_DATA, VECTOR_BASE + 8);
+
+    // The slave is connected to IRQ 2 of the master
+    outb(MASTER_DATA, 1 << 2);
+    outb(SLAVE_DATA, 2);
+
+    outb(MASTER_DATA, ICW4_8086);
+    outb(SLAVE_DATA, ICW4_8086);
+
+    outb(MASTER_DATA, 0xFF);
+    outb(SLAVE_DATA, 0xFF);
+
+    trace!("Disabled the legacy PIC");
+}
@@ -0,0 +1,49 @@
+use x86::io::{inb, outb};
+
+const CHANNEL_2_DATA: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+
+/// Controls the gate of channel 2 (bit 0) and the speaker (bit 1), bit 5
+/// reflects the output of channel 2.
+const PORT_B: u16 = 0x61;
+const PORT_B_GATE: u8 = 1 << 0;
+const PORT_B_SPEAKER: u8 = 1 << 1;
+const PORT_B_OUTPUT: u8 = 1 << 5;
+
+/// The input frequency of the programmable interval timer in Hz.
+pub const FREQUENCY: u64 = 1_193_182;
+
+/// Busy waits for the given number of milliseconds on PIT channel 2, used to
+/// calibrate other timers against it.
+///
+/// # Arguments
+/// * `ms` - The time to wait in milliseconds, at most 54.
+/// * `start` - Called right as the channel starts counting.
+///
+/// # Safety
+///
+/// Nothing else may use PIT channel 2 at the same time.
+///
+pub unsafe fn wait_ms(ms: u64, start: impl FnOnce()) {
+    let count = FREQUENCY * ms / 1000;
+    assert!(count <= u16::MAX as u64, "Cannot wait {} ms on the PIT", ms);
+
+    // Gate low and speaker off, the channel doesn't count yet
+    let port_b = inb(PORT_B) & !(PORT_B_GATE | PORT_B_SPEAKER);
+    outb(PORT_B, port_b);
+
+    // Channel 2, lobyte/hibyte, interrupt on terminal count
+    outb(COMMAND, 0b1011_0000);
+    outb(CHANNEL_2_DATA, count as u8);
+    outb(CHANNEL_2_DATA, (count >> 8) as u8);
+
+    // Start counting and wait for the output to rise
+    outb(PORT_B, port_b | PORT_B_GATE);
+    start();
+
+    while inb(PORT_B) & PORT_B_OUTPUT == 0 {
+        core::hint::spin_loop();
+    }
+
+    outb(PORT_B, port_b);
+}
@@ -0,0 +1,595 @@
+use core::{
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use ::acpi::{
+    fadt::Fadt,
+    platform::address::{AddressSpace, GenericAddress},
+    sdt::Signature,
+    AmlTable,
+};
+use alloc::{boxed::Box, vec};
+use aml::{value::Args, AmlContext, AmlName, AmlValue, DebugVerbosity, Handler};
+use spin::{Mutex, Once};
+use x86::io::{inb, inl, inw, outb, outl, outw};
+
+use super::{
+    acpi, clock, idt,
+    interrupts::InterruptContext,
+    ioapic::{self, Polarity, TriggerMode},
+    irq, lapic, mmu,
+};
+use crate::{arch::PhysicalAddress, sync::WaitQueue, task};
+
+/// Set in PM1 control once the firmware handed ACPI events over to us.
+const PM1_CONTROL_SCI_ENABLE: u16 = 1 << 0;
+const PM1_CONTROL_SLEEP_TYPE_SHIFT: u16 = 10;
+const PM1_CONTROL_SLEEP_ENABLE: u16 = 1 << 13;
+
+/// The power button bit of the PM1 status and enable registers.
+const PM1_EVENT_POWER_BUTTON: u16 = 1 << 8;
+
+/// How long the firmware gets to switch into ACPI mode.
+const ACPI_ENABLE_TIMEOUT_NS: u64 = 1_000_000_000;
+
+/// The registers and values needed to change the power state of the machine.
+///
+/// They are copied out of the FADT and the AML namespace during boot, so
+/// shutting down doesn't depend on any lock that might be held already.
+///
+struct PowerInfo {
+    pm1a_event: GenericAddress,
+    pm1b_event: Option<GenericAddress>,
+    pm1a_control: GenericAddress,
+    pm1b_control: Option<GenericAddress>,
+
+    gpe0: Option<GenericAddress>,
+    gpe1: Option<GenericAddress>,
+
+    /// The `SLP_TYPa` and `SLP_TYPb` values of `\_S5`.
+    s5_sleep_types: Option<(u16, u16)>,
+
+    reset_register: Option<(GenericAddress, u8)>,
+}
+
+static POWER_INFO: Once<PowerInfo> = Once::new();
+
+static AML_CONTEXT: Mutex<Option<AmlContext>> = Mutex::new(None);
+
+/// Latched by the SCI handler, the shutdown itself happens on a thread.
+static POWER_BUTTON_PRESSED: AtomicBool = AtomicBool::new(false);
+
+/// The thread waiting for `POWER_BUTTON_PRESSED`.
+static POWER_EVENTS: WaitQueue = WaitQueue::new();
+
+/// Gives the AML interpreter access to memory, I/O ports and the PCI configuration space.
+struct KernelAmlHandler;
+
+impl KernelAmlHandler {
+    fn memory<T>(address: usize) -> *mut T {
+        // Everything in the memory map is in the direct map already, cached
+        // according to its type (firmware memory write-back, reserved ranges
+        // uncached). Only addresses outside of it get mapped here, those are
+        // device registers and have to be uncached.
+        unsafe {
+            mmu::map_mmio(
+                PhysicalAddress::new(address as u64),
+                core::mem::size_of::<T>() as u64,
+            )
+            .as_mut_ptr()
+        }
+    }
+
+    /// Selects a dword of the configuration space through configuration mechanism #1.
+    fn select_pci(segment: u16, bus: u8, device: u8, function: u8, offset: u16) {
+        assert!(segment == 0, "PCI segment {} is not supported", segment);
+
+        let address = 1 << 31
+            | (bus as u32) << 16
+            | (device as u32) << 11
+            | (function as u32) << 8
+            | (offset as u32 & 0xFC);
+
+        unsafe { outl(0xCF8, address) };
+    }
+}
+
+impl Handler for KernelAmlHandler {
+    fn read_u8(&self, address: usize) -> u8 {
+        unsafe { ptr::read_volatile(Self::memory(address)) }
+    }
+
+    fn read_u16(&self, address: usize) -> u16 {
+        unsafe { ptr::read_volatile(Self::memory(address)) }
+    }
+
+    fn read_u32(&self, address: usize) -> u32 {
+        unsafe { ptr::read_volatile(Self::memory(address)) }
+    }
+
+    fn read_u64(&self, address: usize) -> u64 {
+        unsafe { ptr::read_volatile(Self::memory(address)) }
+    }
+
+    fn write_u8(&mut self, address: usize, value: u8) {
+        unsafe { ptr::write_volatile(Self::memory(address), value) }
+    }
+
+    fn write_u16(&mut self, address: usize, value: u16) {
+        unsafe { ptr::write_volatile(Self::memory(address), value) }
+    }
+
+    fn write_u32(&mut self, address: usize, value: u32) {
+        unsafe { ptr::write_volatile(Self::memory(address), value) }
+    }
+
+    fn write_u64(&mut self, address: usize, value: u64) {
+        unsafe { ptr::write_volatile(Self::memory(address), value) }
+    }
+
+    fn read_io_u8(&self, port: u16) -> u8 {
+        unsafe { inb(port) }
+    }
+
+    fn read_io_u16(&self, port: u16) -> u16 {
+        unsafe { inw(port) }
+    }
+
+    fn read_io_u32(&self, port: u16) -> u32 {
+        unsafe { inl(port) }
+    }
+
+    fn write_io_u8(&self, port: u16, value: u8) {
+        unsafe { outb(port, value) }
+    }
+
+    fn write_io_u16(&self, port: u16, value: u16) {
+        unsafe { outw(port, value) }
+    }
+
+    fn write_io_u32(&self, port: u16, value: u32) {
+        unsafe { outl(port, value) }
+    }
+
+    fn read_pci_u8(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u8 {
+        Self::select_pci(segment, bus, device, function, offset);
+        unsafe { inb(0xCFC + (offset & 0b11)) }
+    }
+
+    fn read_pci_u16(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u16 {
+        Self::select_pci(segment, bus, device, function, offset);
+        unsafe { inw(0xCFC + (offset & 0b10)) }
+    }
+
+    fn read_pci_u32(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+        Self::select_pci(segment, bus, device, function, offset);
+        unsafe { inl(0xCFC) }
+    }
+
+    fn write_pci_u8(
+        &self,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        value: u8,
+    ) {
+        Self::select_pci(segment, bus, device, function, offset);
+        unsafe { outb(0xCFC + (offset & 0b11), value) }
+    }
+
+    fn write_pci_u16(
+        &self,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        value: u16,
+    ) {
+        Self::select_pci(segment, bus, device, function, offset);
+        unsafe { outw(0xCFC + (offset & 0b10), value) }
+    }
+
+    fn write_pci_u32(
+        &self,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        value: u32,
+    ) {
+        Self::select_pci(segment, bus, device, function, offset);
+        unsafe { outl(0xCFC, value) }
+    }
+}
+
+/// Reads a PM1 register, these are 16 bits wide.
+unsafe fn read_register(register: &GenericAddress) -> u16 {
+    match register.address_space {
+        AddressSpace::SystemIo => inw(register.address as u16),
+        AddressSpace::SystemMemory => {
+            ptr::read_volatile(mmu::map_mmio(PhysicalAddress::new(register.address), 2).as_ptr())
+        }
+        space => panic!("Unsupported register address space {:?}", space),
+    }
+}
+
+/// Writes a PM1 register, these are 16 bits wide.
+unsafe fn write_register(register: &GenericAddress, value: u16) {
+    match register.address_space {
+        AddressSpace::SystemIo => outw(register.address as u16, value),
+        AddressSpace::SystemMemory => ptr::write_volatile(
+            mmu::map_mmio(PhysicalAddress::new(register.address), 2).as_mut_ptr(),
+            value,
+        ),
+        space => panic!("Unsupported register address space {:?}", space),
+    }
+}
+
+/// Returns the address of the enable register of a PM1 event block, it
+/// follows the status register which takes up the first half of the block.
+fn pm1_enable_register(event_block: &GenericAddress) -> GenericAddress {
+    GenericAddress {
+        address: event_block.address + event_block.bit_width as u64 / 16,
+        bit_width: event_block.bit_width / 2,
+        ..*event_block
+    }
+}
+
+/// Reads a byte of a GPE block, these are accessed a byte at a time.
+unsafe fn read_gpe(block: &GenericAddress, offset: u64) -> u8 {
+    match block.address_space {
+        AddressSpace::SystemIo => inb((block.address + offset) as u16),
+        AddressSpace::SystemMemory => ptr::read_volatile(
+            mmu::map_mmio(PhysicalAddress::new(block.address + offset), 1).as_ptr(),
+        ),
+        space => panic!("Unsupported register address space {:?}", space),
+    }
+}
+
+/// Writes a byte of a GPE block, these are accessed a byte at a time.
+unsafe fn write_gpe(block: &GenericAddress, offset: u64, value: u8) {
+    match block.address_space {
+        AddressSpace::SystemIo => outb((block.address + offset) as u16, value),
+        AddressSpace::SystemMemory => ptr::write_volatile(
+            mmu::map_mmio(PhysicalAddress::new(block.address + offset), 1).as_mut_ptr(),
+            value,
+        ),
+        space => panic!("Unsupported register address space {:?}", space),
+    }
+}
+
+/// Calls `f` with every GPE block and the length of its status half in
+/// bytes, the enable registers follow the status registers.
+fn for_each_gpe_block(info: &PowerInfo, mut f: impl FnMut(&GenericAddress, u64)) {
+    for block in [info.gpe0, info.gpe1].iter().flatten() {
+        f(block, block.bit_width as u64 / 16);
+    }
+}
+
+/// Masks every general purpose event and clears its status.
+///
+/// Nothing runs the `_Lxx` and `_Exx` methods that would service them, so
+/// an enabled level triggered GPE would keep the SCI asserted forever.
+///
+unsafe fn disable_gpes(info: &PowerInfo) {
+    for_each_gpe_block(info, |block, half| {
+        for offset in 0..half {
+            write_gpe(block, half + offset, 0);
+            write_gpe(block, offset, 0xFF);
+        }
+    });
+}
+
+/// Masks and clears every GPE that raised the SCI.
+unsafe fn acknowledge_gpes(info: &PowerInfo) {
+    for_each_gpe_block(info, |block, half| {
+        for offset in 0..half {
+            let status = read_gpe(block, offset);
+            if status == 0 {
+                continue;
+            }
+
+            let enable = read_gpe(block, half + offset);
+            write_gpe(block, half + offset, enable & !status);
+
+            // Status bits are cleared by writing ones
+            write_gpe(block, offset, status);
+
+            trace!(
+                "Masked unhandled GPEs {:#x} of block {:#x} byte {}",
+                status,
+                block.address,
+                offset
+            );
+        }
+    });
+}
+
+/// Loads the DSDT and SSDTs into the AML interpreter, switches the firmware
+/// into ACPI mode and routes the power button to `spawn_event_thread`.
+///
+/// # Safety
+///
+/// The ACPI tables, the clock and the I/O APIC have to be initialized already.
+///
+pub unsafe fn init() {
+    let mut context = AmlContext::new(Box::new(KernelAmlHandler), DebugVerbosity::None);
+
+    let (fadt, smi_command, acpi_enable, sci_interrupt) = acpi::with_tables(|tables| {
+        if let Some(dsdt) = &tables.dsdt {
+            load_table(&mut context, "DSDT", dsdt);
+        }
+
+        for ssdt in &tables.ssdts {
+            load_table(&mut context, "SSDT", ssdt);
+        }
+
+        let fadt = tables
+            .get_sdt::<Fadt>(Signature::FADT)
+            .ok()
+            .flatten()
+            .expect("The platform has no FADT");
+
+        let smi_command = fadt.smi_cmd_port;
+        let acpi_enable = fadt.acpi_enable;
+        let sci_interrupt = fadt.sci_interrupt;
+
+        (fadt, smi_command, acpi_enable, sci_interrupt)
+    });
+
+    if let Err(error) = context.initialize_objects() {
+        error!("Failed to initialize the AML namespace: {:?}", error);
+    }
+
+    let s5_sleep_types = match context
+        .namespace
+        .get_by_path(&AmlName::from_str("\\_S5").unwrap())
+    {
+        Ok(AmlValue::Package(values)) if values.len() >= 2 => {
+            let sleep_type = |value: &AmlValue| value.as_integer(&context).ok().map(|v| v as u16);
+            sleep_type(&values[0]).zip(sleep_type(&values[1]))
+        }
+        _ => None,
+    };
+
+    if s5_sleep_types.is_none() {
+        error!("The firmware doesn't provide \\_S5, ACPI shutdown is unavailable");
+    }
+
+    let flags = fadt.flags;
+    let reset_value = fadt.reset_value;
+    let reset_register = flags
+        .supports_system_reset_via_fadt()
+        .then(|| fadt.reset_register().ok())
+        .flatten()
+        .map(|register| (register, reset_value));
+
+    let info = POWER_INFO.call_once(|| PowerInfo {
+        pm1a_event: fadt.pm1a_event_block().expect("Invalid PM1a event block"),
+        pm1b_event: fadt.pm1b_event_block().ok().flatten(),
+        pm1a_control: fadt
+            .pm1a_control_block()
+            .expect("Invalid PM1a control block"),
+        pm1b_control: fadt.pm1b_control_block().ok().flatten(),
+        gpe0: fadt.gpe0_block().ok().flatten(),
+        gpe1: fadt.gpe1_block().ok().flatten(),
+        s5_sleep_types,
+        reset_register,
+    });
+
+    *AML_CONTEXT.lock() = Some(context);
+
+    // Hardware reduced platforms and platforms that boot in ACPI mode have no SMI command port
+    if smi_command != 0
+        && acpi_enable != 0
+        && read_register(&info.pm1a_control) & PM1_CONTROL_SCI_ENABLE == 0
+    {
+        outb(smi_command as u16, acpi_enable);
+
+        let deadline = clock::monotonic_ns().saturating_add(ACPI_ENABLE_TIMEOUT_NS);
+        while read_register(&info.pm1a_control) & PM1_CONTROL_SCI_ENABLE == 0 {
+            if clock::monotonic_ns() >= deadline {
+                error!("The firmware did not switch into ACPI mode, no power button events");
+                return;
+            }
+
+            core::hint::spin_loop();
+        }
+
+        trace!("Switched the firmware into ACPI mode");
+    }
+
+    disable_gpes(info);
+    enable_power_button(info);
+    route_sci(sci_interrupt);
+
+    info!(
+        "ACPI power management enabled (SCI on IRQ {}, reset register: {})",
+        sci_interrupt,
+        info.reset_register.is_some()
+    );
+}
+
+unsafe fn load_table(context: &mut AmlContext, name: &str, table: &AmlTable) {
+    let stream = core::slice::from_raw_parts(
+        mmu::map_physical_region(
+            PhysicalAddress::new(table.address as u64),
+            table.length as u64,
+        )
+        .as_ptr::<u8>(),
+        table.length as usize,
+    );
+
+    match context.parse_table(stream) {
+        Ok(()) => trace!(
+            "Loaded {} at {:#x} ({} bytes)",
+            name,
+            table.address,
+            table.length
+        ),
+        Err(error) => error!(
+            "Failed to parse {} at {:#x}: {:?}",
+            name, table.address, error
+        ),
+    }
+}
+
+unsafe fn enable_power_button(info: &PowerInfo) {
+    for event_block in [Some(info.pm1a_event), info.pm1b_event].iter().flatten() {
+        // Status bits are cleared by writing ones
+        write_register(event_block, PM1_EVENT_POWER_BUTTON);
+
+        let enable = pm1_enable_register(event_block);
+        write_register(&enable, read_register(&enable) | PM1_EVENT_POWER_BUTTON);
+    }
+}
+
+fn route_sci(sci_interrupt: u16) {
+    let vector = irq::allocate_vector().expect("No free vector for the SCI");
+    irq::register_irq(vector, sci_handler);
+
+    // The SCI is a shareable, level triggered, active low interrupt unless
+    // the MADT says otherwise. Values past the ISA range are GSIs already,
+    // which can't have an interrupt source override.
+    let (gsi, polarity, trigger_mode) = match u8::try_from(sci_interrupt) {
+        Ok(irq) if (irq as usize) < ioapic::ISA_IRQ_COUNT => {
+            let route = ioapic::isa_route(irq);
+            if route.overridden {
+                (route.gsi, route.polarity, route.trigger_mode)
+            } else {
+                (route.gsi, Polarity::ActiveLow, TriggerMode::Level)
+            }
+        }
+        _ => (
+            sci_interrupt as u32,
+            Polarity::ActiveLow,
+            TriggerMode::Level,
+        ),
+    };
+
+    ioapic::route(gsi, vector, lapic::id(), polarity, trigger_mode);
+}
+
+fn sci_handler(_context: &InterruptContext) {
+    let Some(info) = POWER_INFO.get() else {
+        return;
+    };
+
+    let mut power_button = false;
+
+    for event_block in [Some(info.pm1a_event), info.pm1b_event].iter().flatten() {
+        unsafe {
+            let status = read_register(event_block);
+            write_register(event_block, status);
+
+            power_button |= status & PM1_EVENT_POWER_BUTTON != 0;
+        }
+    }
+
+    unsafe { acknowledge_gpes(info) };
+
+    // Shutting down runs AML and can take a while, that has no place in an
+    // interrupt handler
+    if power_button {
+        POWER_BUTTON_PRESSED.store(true, Ordering::Release);
+        POWER_EVENTS.notify_one();
+    }
+}
+
+/// Starts the thread that shuts the machine down once the power button is pressed.
+///
+/// Presses before this is called are not lost, the thread shuts down right away.
+///
+pub fn spawn_event_thread() {
+    task::spawn("acpi-power", || {
+        POWER_EVENTS.wait_until(|| POWER_BUTTON_PRESSED.load(Ordering::Acquire));
+
+        info!("Power button pressed, shutting down");
+        shutdown();
+    });
+}
+
+/// Turns the machine off by entering the ACPI S5 (soft-off) state.
+///
+/// Halts the CPU if the firmware doesn't support S5.
+///
+pub fn shutdown() -> ! {
+    idt::disable();
+
+    // Give the firmware a chance to prepare, the interpreter is skipped if it
+    // happens to be busy since we might have interrupted it
+    if let Some(context) = AML_CONTEXT.try_lock().as_mut().and_then(|c| c.as_mut()) {
+        let prepare = AmlName::from_str("\\_PTS").unwrap();
+        let args = Args::from_list(vec![AmlValue::Integer(5)]).unwrap();
+
+        if let Err(error) = context.invoke_method(&prepare, args) {
+            trace!("\\_PTS failed: {:?}", error);
+        }
+    }
+
+    if let Some(info) = POWER_INFO.get() {
+        if let Some((sleep_type_a, sleep_type_b)) = info.s5_sleep_types {
+            unsafe {
+                let enter = |control: &GenericAddress, sleep_type: u16| {
+                    let value = read_register(control) & !(0b111 << PM1_CONTROL_SLEEP_TYPE_SHIFT)
+                        | sleep_type << PM1_CONTROL_SLEEP_TYPE_SHIFT
+                        | PM1_CONTROL_SLEEP_ENABLE;
+
+                    write_register(control, value);
+                };
+
+                enter(&info.pm1a_control, sleep_type_a);
+
+                if let Some(pm1b_control) = &info.pm1b_control {
+                    enter(pm1b_control, sleep_type_b);
+                }
+            }
+        }
+    }
+
+    error!("ACPI shutdown failed, halting");
+    super::hcf();
+}
+
+/// Resets the machine through the FADT reset register, falling back to the
+/// keyboard controller.
+///
+pub fn reboot() -> ! {
+    idt::disable();
+
+    if let Some((register, value)) = POWER_INFO.get().and_then(|info| info.reset_register) {
+        unsafe {
+            match register.address_space {
+                AddressSpace::SystemIo => outb(register.address as u16, value),
+                AddressSpace::SystemMemory => ptr::write_volatile(
+                    mmu::map_mmio(PhysicalAddress::new(register.address), 1).as_mut_ptr(),
+                    value,
+                ),
+                AddressSpace::PciConfigSpace => {
+                    let device = (register.address >> 32) as u8;
+                    let function = (register.address >> 16) as u8;
+                    let offset = register.address as u16;
+
+                    KernelAmlHandler.write_pci_u8(0, 0, device, function, offset, value);
+                }
+                space => error!("Unsupported reset register address space {:?}", space),
+            }
+        }
+    }
+
+    // Pulse the reset line of the keyboard controller
+    unsafe {
+        while inb(0x64) & 0b10 != 0 {
+            core::hint::spin_loop();
+        }
+        outb(0x64, 0xFE);
+    }
+
+    error!("Reboot failed, halting");
+    super::hcf();
+}
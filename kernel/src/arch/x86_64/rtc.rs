@@ -0,0 +1,231 @@
+use core::fmt;
+
+use ::acpi::{fadt::Fadt, sdt::Signature};
+use spin::Mutex;
+use x86::io::{inb, outb};
+
+use super::{acpi, clock, irq::without_interrupts};
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+/// Keeps NMIs disabled while a CMOS register is selected.
+const CMOS_NMI_DISABLE: u8 = 1 << 7;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+
+/// Set in the hours register for PM times in 12 hour mode.
+const HOURS_PM: u8 = 1 << 7;
+
+/// Serializes access to the index and data ports.
+static CMOS_LOCK: Mutex<()> = Mutex::new(());
+
+/// A date and time in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+}
+
+impl DateTime {
+    /// Returns the seconds passed since the unix epoch.
+    pub fn to_unix_seconds(self) -> u64 {
+        // Howard Hinnant's days_from_civil, with years starting in March so
+        // the leap day is the last day of the year
+        let year = self.year as i64 - (self.month <= 2) as i64;
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = (self.month as i64 + 9) % 12;
+        let day_of_year = (153 * month + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+
+        let seconds = days * 86_400
+            + self.hours as i64 * 3600
+            + self.minutes as i64 * 60
+            + self.seconds as i64;
+
+        seconds.max(0) as u64
+    }
+
+    /// Converts the seconds passed since the unix epoch into a date and time.
+    ///
+    /// # Arguments
+    /// * `seconds` - The seconds passed since the unix epoch.
+    ///
+    pub fn from_unix_seconds(seconds: u64) -> Self {
+        // Howard Hinnant's civil_from_days
+        let days = (seconds / 86_400) as i64 + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let month = if month < 10 { month + 3 } else { month - 9 };
+        let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+        let time = seconds % 86_400;
+
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hours: (time / 3600) as u8,
+            minutes: (time / 60 % 60) as u8,
+            seconds: (time % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hours, self.minutes, self.seconds
+        )
+    }
+}
+
+unsafe fn read_register(reg: u8) -> u8 {
+    // An NMI or interrupt between selecting and reading the register could
+    // leave the CMOS in an undefined state
+    without_interrupts(|| {
+        outb(CMOS_INDEX, CMOS_NMI_DISABLE | reg);
+        let value = inb(CMOS_DATA);
+
+        // The index port is write only, NMIs are only ever disabled here
+        outb(CMOS_INDEX, reg);
+
+        value
+    })
+}
+
+/// The raw register values of a single read.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct RawTime([u8; 7]);
+
+unsafe fn read_raw(century_register: u8) -> RawTime {
+    while read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+
+    let century = if century_register != 0 {
+        read_register(century_register)
+    } else {
+        0
+    };
+
+    RawTime([
+        read_register(REG_SECONDS),
+        read_register(REG_MINUTES),
+        read_register(REG_HOURS),
+        read_register(REG_DAY),
+        read_register(REG_MONTH),
+        read_register(REG_YEAR),
+        century,
+    ])
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+/// Returns the CMOS index of the century register, or 0 if the firmware doesn't provide one.
+fn century_register() -> u8 {
+    acpi::with_tables(|tables| {
+        let fadt = unsafe { tables.get_sdt::<Fadt>(Signature::FADT) };
+        fadt.ok().flatten().map_or(0, |fadt| fadt.century)
+    })
+}
+
+/// Reads the current date and time from the RTC.
+///
+/// The RTC is assumed to run in UTC.
+///
+pub fn read() -> DateTime {
+    let century_register = century_register();
+    let _guard = CMOS_LOCK.lock();
+
+    let (raw, status_b) = unsafe {
+        // An update may still slip in between the registers, so read until
+        // two reads in a row agree
+        let mut raw = read_raw(century_register);
+        loop {
+            let again = read_raw(century_register);
+            if again == raw {
+                break;
+            }
+
+            raw = again;
+        }
+
+        (raw, read_register(REG_STATUS_B))
+    };
+
+    let [seconds, minutes, hours, day, month, year, century] = raw.0;
+    let pm = hours & HOURS_PM != 0;
+    let hours = hours & !HOURS_PM;
+
+    let convert = |value: u8| {
+        if status_b & STATUS_B_BINARY != 0 {
+            value
+        } else {
+            from_bcd(value)
+        }
+    };
+
+    let mut hours = convert(hours);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 AM is midnight and 12 PM is noon
+        hours %= 12;
+        if pm {
+            hours += 12;
+        }
+    }
+
+    let century = if century_register != 0 {
+        convert(century) as u16
+    } else {
+        20
+    };
+
+    DateTime {
+        year: century * 100 + convert(year) as u16,
+        month: convert(month),
+        day: convert(day),
+        hours,
+        minutes: convert(minutes),
+        seconds: convert(seconds),
+    }
+}
+
+/// Reads the RTC and seeds the wall clock with it.
+///
+/// # Safety
+///
+/// The ACPI tables and the monotonic clock have to be initialized already.
+///
+pub unsafe fn init() {
+    let now = read();
+    clock::set_realtime_ns(now.to_unix_seconds() * 1_000_000_000);
+
+    info!("RTC: {}", now);
+}
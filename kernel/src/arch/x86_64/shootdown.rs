@@ -0,0 +1,138 @@
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use spin::Mutex;
+use x86::tlb;
+
+use super::{
+    clock,
+    interrupts::InterruptContext,
+    ipi::{self, Destination},
+    irq::{self, without_interrupts},
+    percpu,
+    smp::{self, MAX_CPUS},
+};
+use crate::arch::VirtualAddress;
+
+/// The vector TLB shootdown requests arrive at.
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0xF0;
+
+/// The number of pages a single request can invalidate.
+const MAX_PAGES: usize = 16;
+
+/// How long the other CPUs get to acknowledge a request.
+const TIMEOUT_NS: u64 = 1_000_000_000;
+
+/// Only a single shootdown is in flight at any time.
+static SHOOTDOWN_LOCK: Mutex<()> = Mutex::new(());
+
+/// The pages to invalidate of the current request.
+static ADDRESSES: [AtomicU64; MAX_PAGES] = [ADDRESS_INIT; MAX_PAGES];
+static ADDRESS_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ADDRESS_INIT: AtomicU64 = AtomicU64::new(0);
+
+/// The CPU ids that haven't flushed their TLB for the current request yet.
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+/// Registers the IPI handler that serves shootdown requests.
+///
+pub fn init() {
+    irq::register_irq(TLB_SHOOTDOWN_VECTOR, shootdown_handler);
+}
+
+/// Invalidates the TLB entries of the given pages on the calling CPU and on
+/// every CPU in `targets`, then waits until all of them are done.
+///
+/// Must not be called while holding a lock other CPUs might spin on with
+/// interrupts disabled, they could never acknowledge the request.
+///
+/// # Arguments
+/// * `targets` - The ids of the CPUs that may cache the pages.
+/// * `pages` - The pages to invalidate.
+///
+pub fn shootdown(targets: u64, pages: &[VirtualAddress]) {
+    // The calling CPU must not change between the local and the remote flush
+    without_interrupts(|| {
+        for page in pages {
+            unsafe { tlb::flush(page.as_u64() as usize) };
+        }
+
+        // Nothing to do while the APs are not running, this also covers the
+        // early boot where the per-CPU data isn't set up yet
+        let online = smp::online_mask();
+        if online.count_ones() <= 1 {
+            return;
+        }
+
+        let targets = targets & online & !(1 << percpu::this_cpu().id);
+        if targets == 0 {
+            return;
+        }
+
+        for chunk in pages.chunks(MAX_PAGES) {
+            request(targets, chunk);
+        }
+    });
+}
+
+fn request(targets: u64, pages: &[VirtualAddress]) {
+    // Another CPU may be waiting for us to acknowledge its request while we
+    // wait for the lock
+    let guard = loop {
+        if let Some(guard) = SHOOTDOWN_LOCK.try_lock() {
+            break guard;
+        }
+
+        serve();
+        core::hint::spin_loop();
+    };
+
+    for (address, page) in ADDRESSES.iter().zip(pages) {
+        address.store(page.as_u64(), Ordering::Relaxed);
+    }
+    ADDRESS_COUNT.store(pages.len(), Ordering::Relaxed);
+    PENDING.store(targets, Ordering::Release);
+
+    if targets == smp::online_mask() & !(1 << percpu::this_cpu().id) {
+        ipi::send(Destination::AllExcludingCurrent, TLB_SHOOTDOWN_VECTOR);
+    } else {
+        for cpu in (0..MAX_CPUS).filter(|id| targets & (1 << id) != 0) {
+            let cpu = percpu::cpu(cpu).expect("Online CPU has no per-CPU data");
+            ipi::send(Destination::Cpu(cpu.lapic_id), TLB_SHOOTDOWN_VECTOR);
+        }
+    }
+
+    let deadline = clock::monotonic_ns().saturating_add(TIMEOUT_NS);
+    while PENDING.load(Ordering::Acquire) != 0 {
+        assert!(
+            clock::monotonic_ns() < deadline,
+            "CPUs {:#b} did not acknowledge a TLB shootdown",
+            PENDING.load(Ordering::Relaxed)
+        );
+
+        core::hint::spin_loop();
+    }
+
+    drop(guard);
+}
+
+/// Performs the pending request if it targets the calling CPU.
+fn serve() {
+    let cpu = 1 << percpu::this_cpu().id;
+
+    if PENDING.load(Ordering::Acquire) & cpu == 0 {
+        return;
+    }
+
+    let count = ADDRESS_COUNT.load(Ordering::Relaxed);
+    for address in &ADDRESSES[..count] {
+        unsafe { tlb::flush(address.load(Ordering::Relaxed) as usize) };
+    }
+
+    PENDING.fetch_and(!cpu, Ordering::Release);
+}
+
+fn shootdown_handler(_context: &InterruptContext) {
+    serve();
+}
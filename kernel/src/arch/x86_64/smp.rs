@@ -0,0 +1,181 @@
+use core::{
+    ptr::addr_of_mut,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use alloc::boxed::Box;
+use limine::{LimineSmpInfo, LimineSmpRequest};
+use x86::current::paging::BASE_PAGE_SIZE;
+
+use super::{
+    allocate_stack, clock,
+    gdt::{self, CpuTables, TssStacks},
+    idt, lapic, mmu, percpu, switch_stack, BOOT_STACK_PAGES,
+};
+
+static mut SMP_REQUEST: LimineSmpRequest = LimineSmpRequest::new(0);
+
+/// The maximum number of CPUs, every CPU has a bit in `ONLINE_CPUS`.
+pub const MAX_CPUS: usize = 64;
+
+/// The ids of the CPUs that finished their initialization, including the BSP.
+static ONLINE_CPUS: AtomicU64 = AtomicU64::new(1);
+
+/// The id the next AP gets, the BSP is always 0.
+static NEXT_CPU_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// The number of CPUs the bootloader reported, including the BSP.
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+/// How long the BSP waits for the APs to come online.
+const STARTUP_TIMEOUT_NS: u64 = 1_000_000_000;
+
+/// Everything an AP needs to set itself up, handed over through `extra_argument`.
+struct ApStartup {
+    tables: CpuTables,
+    tss_stacks: TssStacks,
+    kernel_stack: u64,
+}
+
+/// Starts every AP and waits until all of them are online, or gives up on
+/// the ones that don't make it within `STARTUP_TIMEOUT_NS`.
+///
+/// The APs start out on stacks and page tables in bootloader reclaimable
+/// memory, so this has to be called before that memory is reclaimed.
+///
+/// # Safety
+///
+/// The local APIC, the IDT and the kernel page tables of the BSP have to be
+/// set up already.
+///
+pub unsafe fn init() {
+    let Some(response) = SMP_REQUEST.get_response().get_mut() else {
+        info!("Bootloader did not provide SMP information, running on the BSP only");
+        return;
+    };
+
+    let bsp_lapic_id = response.bsp_lapic_id;
+    let cpu_count = response.cpu_count as usize;
+
+    assert!(
+        cpu_count <= MAX_CPUS,
+        "{} CPUs found, at most {} are supported",
+        cpu_count,
+        MAX_CPUS
+    );
+
+    CPU_COUNT.store(cpu_count, Ordering::Relaxed);
+
+    for info in response.cpus() {
+        if info.lapic_id == bsp_lapic_id {
+            continue;
+        }
+
+        let tss_stack_pages = gdt::STACK_SIZE / BASE_PAGE_SIZE;
+
+        let startup = Box::new(ApStartup {
+            tables: CpuTables::new(),
+            tss_stacks: TssStacks {
+                privilege: allocate_stack(tss_stack_pages),
+                double_fault: allocate_stack(tss_stack_pages),
+                nmi: allocate_stack(tss_stack_pages),
+                machine_check: allocate_stack(tss_stack_pages),
+            },
+            kernel_stack: allocate_stack(BOOT_STACK_PAGES),
+        });
+
+        info.extra_argument = Box::into_raw(startup) as u64;
+
+        // Writing the entry point wakes the AP up, everything else has to be in place
+        info.goto_address = ap_start;
+    }
+
+    let deadline = clock::monotonic_ns().saturating_add(STARTUP_TIMEOUT_NS);
+    while cpus_online() < cpu_count && clock::monotonic_ns() < deadline {
+        core::hint::spin_loop();
+    }
+
+    if all_cpus_online() {
+        info!("All {} CPUs are online", cpu_count);
+        return;
+    }
+
+    error!(
+        "Only {} of {} CPUs came online, continuing without the others",
+        cpus_online(),
+        cpu_count
+    );
+
+    for info in response.cpus() {
+        let started = (0..MAX_CPUS)
+            .filter_map(percpu::cpu)
+            .any(|cpu| cpu.lapic_id == info.lapic_id);
+
+        // The CPU may still wake up late and use its startup data and stacks,
+        // so they are leaked on purpose instead of freed
+        if !started {
+            error!(
+                "  CPU with APIC id {} did not start, leaking its startup data and stacks",
+                info.lapic_id
+            );
+        }
+    }
+}
+
+/// Marks the calling CPU as online, other CPUs may send it work from now on.
+///
+/// Must only be called once the CPU is completely set up, including its
+/// scheduler. The BSP reclaims the bootloader memory as soon as every AP is
+/// online, so nothing on the limine stack may be used afterwards.
+///
+pub fn mark_online() {
+    ONLINE_CPUS.fetch_or(1 << percpu::this_cpu().id, Ordering::Release);
+}
+
+/// Returns whether every CPU the bootloader reported is up and running.
+pub fn all_cpus_online() -> bool {
+    cpus_online() == CPU_COUNT.load(Ordering::Relaxed)
+}
+
+/// Returns the number of CPUs that are up and running.
+pub fn cpus_online() -> usize {
+    online_mask().count_ones() as usize
+}
+
+/// Returns a mask with the bit of every CPU id that is up and running.
+pub fn online_mask() -> u64 {
+    ONLINE_CPUS.load(Ordering::Acquire)
+}
+
+extern "C" fn ap_start(info: *const LimineSmpInfo) -> ! {
+    unsafe {
+        // Everything the kernel allocated is only mapped in its own page tables
+        mmu::init_ap();
+
+        let info = &*info;
+        let startup = &mut *(info.extra_argument as *mut ApStartup);
+
+        idt::load();
+        let tables = addr_of_mut!(startup.tables);
+        gdt::load(&mut *tables, &startup.tss_stacks);
+
+        lapic::enable();
+
+        let id = NEXT_CPU_ID.fetch_add(1, Ordering::Relaxed);
+        percpu::init(id, &mut *tables);
+
+        // The handlers need the per-CPU data before interrupts can be turned on
+        idt::enable();
+
+        switch_stack(startup.kernel_stack, ap_start_on_kernel_stack)
+    }
+}
+
+extern "C" fn ap_start_on_kernel_stack() -> ! {
+    let cpu = percpu::this_cpu();
+    info!("CPU - {} (AP) started, APIC id {}", cpu.id, cpu.lapic_id);
+
+    // The CPU only counts as online once its scheduler runs, `ap_main`
+    // takes care of that
+    crate::ap_main();
+}
@@ -13,6 +13,8 @@ use core::{arch::asm, panic::PanicInfo};
 
 mod allocator;
 mod arch;
+mod sync;
+mod task;
 
 #[macro_use]
 extern crate log;
@@ -31,12 +33,38 @@ extern crate lazy_static;
 
 extern crate static_assertions as sa;
 
+/// The `MODULE_CMDLINE` of the first program to run, see `limine.cfg`.
+const INIT_MODULE: &str = "init";
+
 fn bsp_main() -> ! {
-    arch::hcf();
+    task::init_cpu();
+    arch::power::spawn_event_thread();
+
+    match arch::modules::find(INIT_MODULE) {
+        Some(init) => match task::elf::load(init.data(), &[&init.path], &[]) {
+            Ok(program) => {
+                info!(
+                    "Loaded {}, entry {:#x}, stack {:#x}",
+                    init.path,
+                    program.entry.as_u64(),
+                    program.stack_pointer.as_u64()
+                );
+
+                // TODO: Enter ring 3 once there are user threads, until then
+                //       the program is only loaded to check that it can be
+                unsafe { program.unload() };
+            }
+            Err(error) => error!("Failed to load {}: {:?}", init.path, error),
+        },
+        None => error!("No module named {:?}, nothing to run", INIT_MODULE),
+    }
+
+    task::exit();
 }
 
 fn ap_main() -> ! {
-    arch::hcf();
+    task::init_cpu();
+    task::exit();
 }
 
 #[panic_handler]
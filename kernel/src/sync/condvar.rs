@@ -0,0 +1,80 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::{
+    mutex::{Mutex, MutexGuard},
+    wait_queue::WaitQueue,
+};
+
+/// A condition variable for use with the sleeping `Mutex`.
+///
+/// Like every condition variable it may wake up spuriously, waiters have to
+/// check their condition in a loop or use `wait_while`.
+///
+pub struct Condvar {
+    /// Bumped by every notification, a waiter sleeps until it changes.
+    sequence: AtomicU64,
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            sequence: AtomicU64::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Releases the mutex, blocks until notified and reacquires the mutex.
+    ///
+    /// # Arguments
+    /// * `guard` - The guard of the mutex protecting the condition.
+    ///
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        // Read before unlocking, a notification sent after the unlock changes it
+        let sequence = self.sequence.load(Ordering::Acquire);
+
+        let mutex = MutexGuard::mutex(&guard);
+        drop(guard);
+
+        self.waiters
+            .wait_until(|| self.sequence.load(Ordering::Acquire) != sequence);
+
+        mutex.lock()
+    }
+
+    /// Blocks until `condition` returns `false`.
+    ///
+    /// # Arguments
+    /// * `guard` - The guard of the mutex protecting the condition.
+    /// * `condition` - Checked with the mutex held.
+    ///
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+
+        guard
+    }
+
+    /// Wakes one waiting thread.
+    pub fn notify_one(&self) {
+        self.sequence.fetch_add(1, Ordering::Release);
+        self.waiters.notify_one();
+    }
+
+    /// Wakes every waiting thread.
+    pub fn notify_all(&self) {
+        self.sequence.fetch_add(1, Ordering::Release);
+        self.waiters.notify_all();
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,15 @@
+pub mod condvar;
+pub mod mutex;
+pub mod rwlock;
+pub mod semaphore;
+pub mod spinlock;
+pub mod wait_queue;
+
+pub use self::{
+    condvar::Condvar,
+    mutex::{Mutex, MutexGuard},
+    rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+    semaphore::Semaphore,
+    spinlock::{IrqSpinlock, IrqSpinlockGuard},
+    wait_queue::WaitQueue,
+};
@@ -0,0 +1,131 @@
+use core::{
+    cell::UnsafeCell,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::arch::x86_64::idt::InterruptGuard;
+
+/// A spinlock that disables interrupts on the local CPU while it is held.
+///
+/// Unlike `spin::Mutex` it can be shared between threads and interrupt
+/// handlers, an interrupt can never try to take the lock its own CPU holds.
+///
+pub struct IrqSpinlock<T: ?Sized> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for IrqSpinlock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for IrqSpinlock<T> {}
+
+/// Releases the lock and restores the interrupt state of the CPU once dropped.
+pub struct IrqSpinlockGuard<'a, T: ?Sized> {
+    lock: &'a IrqSpinlock<T>,
+
+    /// Dropped after the lock is released.
+    interrupts: InterruptGuard,
+}
+
+impl<T> IrqSpinlock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> IrqSpinlock<T> {
+    /// Disables interrupts and spins until the lock is acquired.
+    pub fn lock(&self) -> IrqSpinlockGuard<T> {
+        let interrupts = InterruptGuard::new();
+
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+
+        IrqSpinlockGuard {
+            lock: self,
+            interrupts,
+        }
+    }
+
+    /// Acquires the lock if it is free, interrupts stay untouched otherwise.
+    pub fn try_lock(&self) -> Option<IrqSpinlockGuard<T>> {
+        let interrupts = InterruptGuard::new();
+
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| IrqSpinlockGuard {
+                lock: self,
+                interrupts,
+            })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// Releases the lock without touching the interrupt state.
+    ///
+    /// # Safety
+    ///
+    /// The lock must be held and its guard must have been leaked with
+    /// `IrqSpinlockGuard::leak`.
+    ///
+    pub unsafe fn force_unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<'a, T: ?Sized> IrqSpinlockGuard<'a, T> {
+    /// Forgets the guard while keeping the lock held and interrupts disabled.
+    ///
+    /// Returns the lock and the guard restoring the interrupt state, the
+    /// caller is responsible for both.
+    ///
+    pub fn leak(this: Self) -> (&'a IrqSpinlock<T>, InterruptGuard) {
+        let this = ManuallyDrop::new(this);
+
+        // SAFETY: The guard is never dropped, so the field is moved out only once
+        (this.lock, unsafe { ptr::read(&this.interrupts) })
+    }
+}
+
+impl<T: ?Sized> Deref for IrqSpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
@@ -0,0 +1,84 @@
+use alloc::{collections::VecDeque, sync::Arc};
+
+use super::spinlock::{IrqSpinlock, IrqSpinlockGuard};
+use crate::task::{
+    scheduler::{self, DeferredUnlock},
+    Thread, ThreadState,
+};
+
+/// A list of threads blocked until some condition becomes true.
+///
+/// Whoever changes the condition has to call `notify_one` or `notify_all`
+/// afterwards. The condition is checked while the queue is locked, so a
+/// notification can't get lost between the check and the thread blocking.
+///
+pub struct WaitQueue {
+    waiters: IrqSpinlock<VecDeque<Arc<Thread>>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: IrqSpinlock::new(VecDeque::new()),
+        }
+    }
+
+    /// Blocks the calling thread until `condition` returns `true`.
+    ///
+    /// # Arguments
+    /// * `condition` - Checked before blocking and after every notification,
+    ///                 with the queue locked and interrupts disabled.
+    ///
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        loop {
+            let mut waiters = self.waiters.lock();
+
+            if condition() {
+                return;
+            }
+
+            let current = scheduler::current();
+            current.set_state(ThreadState::Blocked);
+            waiters.push_back(current);
+
+            // The queue stays locked until the thread is off its CPU, so it
+            // can't be woken while still running
+            let (lock, _interrupts) = IrqSpinlockGuard::leak(waiters);
+            unsafe { scheduler::block(Some(DeferredUnlock::irq_spin(lock))) };
+        }
+    }
+
+    /// Wakes the thread that waited longest.
+    ///
+    /// Returns `false` if no thread was waiting.
+    ///
+    pub fn notify_one(&self) -> bool {
+        let Some(thread) = self.waiters.lock().pop_front() else {
+            return false;
+        };
+
+        scheduler::wake(&thread);
+        true
+    }
+
+    /// Wakes every waiting thread, returns how many there were.
+    pub fn notify_all(&self) -> usize {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+
+        for thread in waiters.iter() {
+            scheduler::wake(thread);
+        }
+
+        waiters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock().is_empty()
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
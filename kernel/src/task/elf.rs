@@ -0,0 +1,648 @@
+use core::{mem::size_of, ops::Range, ptr};
+
+use alloc::vec::Vec;
+use x86::current::paging::BASE_PAGE_SIZE;
+
+use crate::{
+    allocator::allocate_pages,
+    arch::{
+        x86_64::mmu, HalMemoryMapper, MemoryMapper, PageFlags, PageSize, PhysicalAddress,
+        VirtualAddress,
+    },
+};
+
+const PAGE_SIZE: u64 = BASE_PAGE_SIZE as u64;
+
+/// Where position independent executables get loaded.
+const PIE_BASE: u64 = 0x0000_0000_0040_0000;
+
+/// The end of the initial user stack, an unmapped page above it stays free.
+const USER_STACK_TOP: u64 = 0x0000_7FFF_FFFF_F000;
+
+/// The size of the initial user stack in pages.
+const USER_STACK_PAGES: u64 = 64;
+
+const ELF_MAGIC: [u8; 4] = *b"\x7FELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const PT_TLS: u32 = 7;
+const PT_GNU_STACK: u32 = 0x6474_E551;
+
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+
+const DT_NULL: i64 = 0;
+const DT_RELA: i64 = 7;
+const DT_RELASZ: i64 = 8;
+const DT_RELAENT: i64 = 9;
+const DT_REL: i64 = 17;
+
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_RELATIVE: u32 = 8;
+
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_ENTRY: u64 = 9;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct FileHeader {
+    ident: [u8; 16],
+    typ: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+sa::const_assert_eq!(size_of::<FileHeader>(), 64);
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct ProgramHeader {
+    typ: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+sa::const_assert_eq!(size_of::<ProgramHeader>(), 56);
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Dynamic {
+    tag: i64,
+    val: u64,
+}
+sa::const_assert_eq!(size_of::<Dynamic>(), 16);
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Rela {
+    offset: u64,
+    info: u64,
+    addend: i64,
+}
+sa::const_assert_eq!(size_of::<Rela>(), 24);
+
+/// Why a binary couldn't be loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// A header or segment reaches past the end of the file.
+    Truncated,
+    /// The file doesn't start with the ELF magic.
+    BadMagic,
+    /// Not a little endian ELF64 file of the current version.
+    UnsupportedFormat,
+    /// Neither an executable nor a position independent executable.
+    UnsupportedType(u16),
+    UnsupportedMachine(u16),
+    /// The binary asks for a dynamic linker.
+    Interpreter,
+    /// A program header is malformed, e.g. its file size exceeds its memory size.
+    BadSegment,
+    /// A segment lies outside of the lower half or overlaps the stack.
+    BadAddress,
+    NoLoadableSegments,
+    /// The entry point isn't inside an executable segment.
+    BadEntry,
+    UnsupportedRelocation(u32),
+    /// The thread local storage template needs more than page alignment.
+    UnsupportedTlsAlignment,
+    /// The arguments and environment don't fit on the initial stack.
+    ArgumentsTooLarge,
+}
+
+/// A program ready to be started in ring 3.
+pub struct LoadedProgram {
+    /// The page tables of the program, the higher half is shared with the kernel.
+    pub address_space: HalMemoryMapper,
+
+    pub entry: VirtualAddress,
+
+    /// The initial stack pointer, it points to `argc` as `_start` expects.
+    pub stack_pointer: VirtualAddress,
+
+    /// The FS base of the initial thread, `None` if the program has no thread local storage.
+    pub thread_pointer: Option<VirtualAddress>,
+}
+
+impl LoadedProgram {
+    /// Frees the address space of the program.
+    ///
+    /// # Safety
+    ///
+    /// The address space must not be active on any CPU.
+    ///
+    pub unsafe fn unload(self) {
+        mmu::destroy_address_space(self.address_space);
+    }
+}
+
+/// A binary that passed validation, loading it can't fail anymore.
+struct Image<'a> {
+    data: &'a [u8],
+    header: FileHeader,
+    segments: Vec<ProgramHeader>,
+    /// Added to every address in the file, non-zero for position independent executables.
+    base: u64,
+    /// The end of the highest segment, after adding `base`.
+    end: u64,
+    relocations: Vec<Rela>,
+    tls: Option<ProgramHeader>,
+    executable_stack: bool,
+}
+
+/// Loads an ELF64 executable into a new user address space.
+///
+/// Both statically linked executables and static position independent
+/// executables (with only relative relocations) are supported.
+///
+/// # Arguments
+/// * `data` - The contents of the executable.
+/// * `argv` - The arguments passed to the program, including its name.
+/// * `envp` - The environment passed to the program, as `KEY=value` strings.
+///
+pub fn load(data: &[u8], argv: &[&str], envp: &[&str]) -> Result<LoadedProgram, ElfError> {
+    let image = parse(data)?;
+
+    let stack_size = initial_stack_size(argv, envp);
+    if stack_size > USER_STACK_PAGES * PAGE_SIZE {
+        return Err(ElfError::ArgumentsTooLarge);
+    }
+
+    let mut address_space = mmu::new_address_space();
+
+    map_segments(&mut address_space, &image);
+    apply_relocations(&mut address_space, &image);
+
+    let thread_pointer = image
+        .tls
+        .map(|tls| map_tls(&mut address_space, &image, &tls));
+
+    let stack_pointer = map_stack(&mut address_space, &image, argv, envp);
+
+    Ok(LoadedProgram {
+        address_space,
+        entry: VirtualAddress::new(image.base + image.header.entry),
+        stack_pointer,
+        thread_pointer,
+    })
+}
+
+/// Returns the `count` structures of type `T` starting at `offset` in the file.
+fn read_array<T: Copy>(data: &[u8], offset: u64, count: usize) -> Result<Vec<T>, ElfError> {
+    let size = count
+        .checked_mul(size_of::<T>())
+        .ok_or(ElfError::Truncated)?;
+    let bytes = file_range(data, offset, size as u64)?;
+
+    // SAFETY: The range was checked and every bit pattern is a valid `T`
+    Ok(bytes
+        .chunks_exact(size_of::<T>())
+        .map(|chunk| unsafe { ptr::read_unaligned(chunk.as_ptr() as *const T) })
+        .collect())
+}
+
+fn file_range(data: &[u8], offset: u64, size: u64) -> Result<&[u8], ElfError> {
+    let end = offset.checked_add(size).ok_or(ElfError::Truncated)?;
+    data.get(offset as usize..end as usize)
+        .ok_or(ElfError::Truncated)
+}
+
+fn parse(data: &[u8]) -> Result<Image, ElfError> {
+    let header = read_array::<FileHeader>(data, 0, 1)?[0];
+
+    if header.ident[..4] != ELF_MAGIC {
+        return Err(ElfError::BadMagic);
+    }
+
+    if header.ident[4] != ELFCLASS64
+        || header.ident[5] != ELFDATA2LSB
+        || header.ident[6] != EV_CURRENT
+        || header.phentsize as usize != size_of::<ProgramHeader>()
+    {
+        return Err(ElfError::UnsupportedFormat);
+    }
+
+    let base = match header.typ {
+        ET_EXEC => 0,
+        ET_DYN => PIE_BASE,
+        typ => return Err(ElfError::UnsupportedType(typ)),
+    };
+
+    if header.machine != EM_X86_64 {
+        return Err(ElfError::UnsupportedMachine(header.machine));
+    }
+
+    let headers = read_array::<ProgramHeader>(data, header.phoff, header.phnum as usize)?;
+
+    let mut image = Image {
+        data,
+        header,
+        segments: Vec::new(),
+        base,
+        end: 0,
+        relocations: Vec::new(),
+        tls: None,
+        executable_stack: false,
+    };
+
+    for phdr in headers.iter() {
+        match phdr.typ {
+            PT_LOAD => {
+                check_segment(&image, phdr)?;
+                image.segments.push(*phdr);
+                image.end = image.end.max(image.base + phdr.vaddr + phdr.memsz);
+            }
+            PT_TLS => {
+                file_range(data, phdr.offset, phdr.filesz)?;
+
+                // The block has to fit into the lower half, `tls_layout`
+                // relies on that to not overflow
+                if phdr.filesz > phdr.memsz || phdr.memsz > stack_bottom() {
+                    return Err(ElfError::BadSegment);
+                }
+                if phdr.align != 0 && !phdr.align.is_power_of_two() {
+                    return Err(ElfError::BadSegment);
+                }
+                if phdr.align > PAGE_SIZE {
+                    return Err(ElfError::UnsupportedTlsAlignment);
+                }
+
+                image.tls = Some(*phdr);
+            }
+            PT_GNU_STACK => image.executable_stack = phdr.flags & PF_X != 0,
+            PT_INTERP => return Err(ElfError::Interpreter),
+            _ => {}
+        }
+    }
+
+    if image.segments.is_empty() {
+        return Err(ElfError::NoLoadableSegments);
+    }
+
+    let entry_mapped = image.segments.iter().any(|segment| {
+        segment.flags & PF_X != 0
+            && (segment.vaddr..segment.vaddr + segment.memsz).contains(&header.entry)
+    });
+    if !entry_mapped {
+        return Err(ElfError::BadEntry);
+    }
+
+    if let Some(tls) = image.tls {
+        let (_, thread_pointer) = tls_layout(&image, &tls);
+        if thread_pointer + 8 > stack_bottom() - PAGE_SIZE {
+            return Err(ElfError::BadAddress);
+        }
+    }
+
+    if let Some(dynamic) = headers.iter().find(|phdr| phdr.typ == PT_DYNAMIC) {
+        image.relocations = parse_relocations(&image, dynamic)?;
+    }
+
+    Ok(image)
+}
+
+fn check_segment(image: &Image, phdr: &ProgramHeader) -> Result<(), ElfError> {
+    file_range(image.data, phdr.offset, phdr.filesz)?;
+
+    if phdr.filesz > phdr.memsz {
+        return Err(ElfError::BadSegment);
+    }
+
+    // The file offset and the address have to share their page offset, or
+    // the segment couldn't be mapped page by page
+    if phdr.align > 1 && (phdr.vaddr % phdr.align != phdr.offset % phdr.align) {
+        return Err(ElfError::BadSegment);
+    }
+
+    let start = image
+        .base
+        .checked_add(phdr.vaddr)
+        .ok_or(ElfError::BadAddress)?;
+    let end = start.checked_add(phdr.memsz).ok_or(ElfError::BadAddress)?;
+
+    if start < PAGE_SIZE || end > stack_bottom() - PAGE_SIZE {
+        return Err(ElfError::BadAddress);
+    }
+
+    Ok(())
+}
+
+/// Reads the relocations the dynamic section points to.
+fn parse_relocations(image: &Image, dynamic: &ProgramHeader) -> Result<Vec<Rela>, ElfError> {
+    let count = dynamic.filesz as usize / size_of::<Dynamic>();
+    let entries = read_array::<Dynamic>(image.data, dynamic.offset, count)?;
+
+    let mut rela = None;
+    let mut rela_size = 0;
+
+    for entry in entries.iter().take_while(|entry| entry.tag != DT_NULL) {
+        match entry.tag {
+            DT_RELA => rela = Some(entry.val),
+            DT_RELASZ => rela_size = entry.val,
+            DT_RELAENT if entry.val as usize != size_of::<Rela>() => {
+                return Err(ElfError::BadSegment)
+            }
+            // x86_64 only uses explicit addends
+            DT_REL => return Err(ElfError::UnsupportedRelocation(0)),
+            _ => {}
+        }
+    }
+
+    let Some(rela) = rela else {
+        return Ok(Vec::new());
+    };
+
+    let offset = file_offset(image, rela).ok_or(ElfError::BadSegment)?;
+    let relocations =
+        read_array::<Rela>(image.data, offset, rela_size as usize / size_of::<Rela>())?;
+
+    for relocation in relocations.iter() {
+        match relocation.info as u32 {
+            R_X86_64_NONE => {}
+            R_X86_64_RELATIVE => {
+                let mapped = image.segments.iter().any(|segment| {
+                    segment.vaddr <= relocation.offset
+                        && relocation.offset.saturating_add(8) <= segment.vaddr + segment.memsz
+                });
+
+                if !mapped {
+                    return Err(ElfError::BadSegment);
+                }
+            }
+            typ => return Err(ElfError::UnsupportedRelocation(typ)),
+        }
+    }
+
+    Ok(relocations)
+}
+
+/// Translates an address of the binary into an offset in its file.
+fn file_offset(image: &Image, vaddr: u64) -> Option<u64> {
+    image
+        .segments
+        .iter()
+        .find(|segment| (segment.vaddr..segment.vaddr + segment.filesz).contains(&vaddr))
+        .map(|segment| segment.offset + (vaddr - segment.vaddr))
+}
+
+/// Maps every `PT_LOAD` segment and copies its contents.
+fn map_segments(address_space: &mut HalMemoryMapper, image: &Image) {
+    for segment in image.segments.iter() {
+        let start = image.base + segment.vaddr;
+        let end = start + segment.memsz;
+
+        let mut flags = PageFlags::USER;
+        if segment.flags & PF_W != 0 {
+            flags |= PageFlags::WRITABLE;
+        }
+        if segment.flags & PF_X == 0 {
+            flags |= PageFlags::NO_EXECUTE;
+        }
+
+        map_zeroed(address_space, page_range(start, end), flags);
+
+        // Everything past the file size stays zero, which takes care of `.bss`
+        let contents = &image.data[segment.offset as usize..][..segment.filesz as usize];
+        write_user(address_space, start, contents);
+    }
+}
+
+fn apply_relocations(address_space: &mut HalMemoryMapper, image: &Image) {
+    for relocation in image.relocations.iter() {
+        if relocation.info as u32 == R_X86_64_RELATIVE {
+            let value = image.base.wrapping_add(relocation.addend as u64);
+            write_user(
+                address_space,
+                image.base + relocation.offset,
+                &value.to_le_bytes(),
+            );
+        }
+    }
+}
+
+/// Returns where the thread local storage block of the initial thread starts
+/// and where its thread pointer points.
+///
+/// x86_64 uses TLS variant II, the block ends right below the thread
+/// pointer, which points to a word containing its own address.
+///
+fn tls_layout(image: &Image, tls: &ProgramHeader) -> (u64, u64) {
+    let block_size = align_up(tls.memsz, tls.align.max(8));
+
+    // Leave an unmapped page between the image and the block
+    let start = align_up(image.end, PAGE_SIZE) + PAGE_SIZE;
+
+    (start, start + block_size)
+}
+
+/// Sets up the thread local storage of the initial thread, returns its thread pointer.
+fn map_tls(
+    address_space: &mut HalMemoryMapper,
+    image: &Image,
+    tls: &ProgramHeader,
+) -> VirtualAddress {
+    let (start, thread_pointer) = tls_layout(image, tls);
+
+    map_zeroed(
+        address_space,
+        page_range(start, thread_pointer + 8),
+        PageFlags::USER | PageFlags::WRITABLE | PageFlags::NO_EXECUTE,
+    );
+
+    let template = &image.data[tls.offset as usize..][..tls.filesz as usize];
+    write_user(address_space, start, template);
+    write_user(address_space, thread_pointer, &thread_pointer.to_le_bytes());
+
+    VirtualAddress::new(thread_pointer)
+}
+
+const fn stack_bottom() -> u64 {
+    USER_STACK_TOP - USER_STACK_PAGES * PAGE_SIZE
+}
+
+/// Returns how much of the initial stack the strings and vectors take up.
+fn initial_stack_size(argv: &[&str], envp: &[&str]) -> u64 {
+    let strings: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum();
+    let words = 1 + argv.len() + 1 + envp.len() + 1 + 2 * AUXV_ENTRIES + 1;
+
+    // Both parts are padded to 16 bytes, like `map_stack` does
+    align_up(strings as u64, 16) + align_up(words as u64 * 8, 16)
+}
+
+/// The number of auxiliary vector entries `map_stack` passes, without `AT_NULL`.
+const AUXV_ENTRIES: usize = 6;
+
+/// Maps the initial stack and fills in what the System V ABI expects `_start`
+/// to find on it, returns the initial stack pointer.
+///
+/// From the stack pointer upwards: `argc`, the `argv` pointers, a null
+/// pointer, the `envp` pointers, a null pointer, the auxiliary vector ending
+/// with `AT_NULL` and finally the strings.
+///
+fn map_stack(
+    address_space: &mut HalMemoryMapper,
+    image: &Image,
+    argv: &[&str],
+    envp: &[&str],
+) -> VirtualAddress {
+    let mut flags = PageFlags::USER | PageFlags::WRITABLE;
+    if !image.executable_stack {
+        flags |= PageFlags::NO_EXECUTE;
+    }
+
+    map_zeroed(address_space, stack_bottom()..USER_STACK_TOP, flags);
+
+    let mut strings = Vec::new();
+    let mut string_offsets = Vec::new();
+
+    for string in argv.iter().chain(envp) {
+        string_offsets.push(strings.len() as u64);
+        strings.extend_from_slice(string.as_bytes());
+        strings.push(0);
+    }
+
+    let strings_start = (USER_STACK_TOP - strings.len() as u64) & !0xF;
+    write_user(address_space, strings_start, &strings);
+
+    let mut words = Vec::new();
+    let mut pointers = string_offsets.iter().map(|offset| strings_start + offset);
+
+    words.push(argv.len() as u64);
+    words.extend(pointers.by_ref().take(argv.len()));
+    words.push(0);
+    words.extend(pointers);
+    words.push(0);
+
+    let auxv = [
+        (AT_PHDR, program_headers_address(image)),
+        (AT_PHENT, size_of::<ProgramHeader>() as u64),
+        (AT_PHNUM, image.header.phnum as u64),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_BASE, 0),
+        (AT_ENTRY, image.base + image.header.entry),
+    ];
+    sa::const_assert_eq!(AUXV_ENTRIES, 6);
+
+    for (key, value) in auxv {
+        words.extend([key, value]);
+    }
+    words.extend([AT_NULL, 0]);
+
+    // `_start` expects the stack pointer to be 16 byte aligned
+    let mut stack_pointer = strings_start - words.len() as u64 * 8;
+    stack_pointer &= !0xF;
+
+    let bytes = words
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect::<Vec<_>>();
+    write_user(address_space, stack_pointer, &bytes);
+
+    VirtualAddress::new(stack_pointer)
+}
+
+/// Returns where the program headers are mapped, 0 if no segment contains them.
+fn program_headers_address(image: &Image) -> u64 {
+    let phdr_size = image.header.phnum as u64 * size_of::<ProgramHeader>() as u64;
+
+    image
+        .segments
+        .iter()
+        .find(|segment| {
+            segment.offset <= image.header.phoff
+                && image.header.phoff + phdr_size <= segment.offset + segment.filesz
+        })
+        .map_or(0, |segment| {
+            image.base + segment.vaddr + (image.header.phoff - segment.offset)
+        })
+}
+
+/// Backs every page in `pages` with a zeroed frame.
+///
+/// Pages shared by two segments keep their frame and get the permissions of both.
+///
+fn map_zeroed(address_space: &mut HalMemoryMapper, pages: Range<u64>, flags: PageFlags) {
+    for page in pages.step_by(BASE_PAGE_SIZE) {
+        let virt = VirtualAddress::new(page);
+
+        if let Some(translation) = address_space.translate(virt) {
+            let mut merged = translation.flags | flags;
+            if !(translation.flags & flags).contains(PageFlags::NO_EXECUTE) {
+                merged.remove(PageFlags::NO_EXECUTE);
+            }
+
+            unsafe { address_space.protect(virt, merged) };
+            continue;
+        }
+
+        unsafe {
+            let frame = PhysicalAddress::from(allocate_pages(1));
+            frame
+                .to_virtual()
+                .as_mut_ptr::<u8>()
+                .write_bytes(0, BASE_PAGE_SIZE);
+
+            address_space.map(frame, virt, PageSize::Size4KiB, flags);
+        }
+    }
+}
+
+/// Copies `bytes` to `virt` in an address space that doesn't have to be active.
+///
+/// Every page written to has to be mapped.
+///
+fn write_user(address_space: &HalMemoryMapper, mut virt: u64, mut bytes: &[u8]) {
+    while !bytes.is_empty() {
+        let translation = address_space
+            .translate(VirtualAddress::new(virt))
+            .expect("Writing to an unmapped user page");
+
+        let chunk = bytes.len().min((PAGE_SIZE - virt % PAGE_SIZE) as usize);
+
+        unsafe {
+            ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                translation.phys.to_virtual().as_mut_ptr::<u8>(),
+                chunk,
+            );
+        }
+
+        virt += chunk as u64;
+        bytes = &bytes[chunk..];
+    }
+}
+
+/// Returns the pages covering `start..end`.
+fn page_range(start: u64, end: u64) -> Range<u64> {
+    start & !(PAGE_SIZE - 1)..align_up(end, PAGE_SIZE)
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) & !(align - 1)
+}
@@ -0,0 +1,10 @@
+pub mod elf;
+pub mod scheduler;
+pub mod thread;
+
+pub use self::{
+    scheduler::{
+        block, current, exit, init_cpu, sleep_ns, spawn, spawn_with_priority, wake, yield_now,
+    },
+    thread::{Priority, Thread, ThreadId, ThreadState},
+};
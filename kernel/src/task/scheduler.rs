@@ -0,0 +1,559 @@
+use core::{
+    cell::Cell,
+    cmp::Ordering as CmpOrdering,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use alloc::{
+    boxed::Box, collections::BinaryHeap, collections::VecDeque, format, string::String, sync::Arc,
+    vec::Vec,
+};
+use spin::{Mutex, Once};
+
+use super::thread::{Priority, Thread, ThreadState};
+use crate::{
+    arch::{
+        clock, this_cpu,
+        x86_64::{
+            context, enable_and_halt, idt,
+            interrupts::InterruptContext,
+            ipi::{self, Destination},
+            irq::{self, without_interrupts},
+            lapic, percpu, smp,
+        },
+    },
+    sync::{IrqSpinlock, IrqSpinlockGuard},
+};
+
+/// How often the timer interrupts every CPU, every tick ends a time slice.
+pub const TICK_HZ: u64 = 100;
+
+/// The vector used to make another CPU look at its run queue.
+pub const RESCHEDULE_VECTOR: u8 = 0xF1;
+
+/// The threads that are ready to run on a CPU, one queue per priority.
+pub struct RunQueue {
+    queues: [VecDeque<Arc<Thread>>; Priority::COUNT],
+}
+
+impl RunQueue {
+    const fn new() -> Self {
+        Self {
+            queues: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+        }
+    }
+
+    fn push(&mut self, thread: Arc<Thread>) {
+        self.queues[thread.priority() as usize].push_back(thread);
+    }
+
+    /// Takes the thread that waited longest out of the highest priority queue.
+    fn pop(&mut self) -> Option<Arc<Thread>> {
+        self.queues.iter_mut().rev().find_map(VecDeque::pop_front)
+    }
+
+    /// Takes the thread that waited the shortest out of the highest priority
+    /// queue, it is the least likely to still be cache hot on this CPU.
+    fn steal(&mut self) -> Option<Arc<Thread>> {
+        self.queues.iter_mut().rev().find_map(VecDeque::pop_back)
+    }
+
+    fn highest_priority(&self) -> Option<Priority> {
+        [Priority::High, Priority::Normal, Priority::Low]
+            .into_iter()
+            .find(|&priority| !self.queues[priority as usize].is_empty())
+    }
+
+    fn len(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+}
+
+/// A lock the scheduler releases once a blocking thread is off its CPU.
+///
+/// Whoever wakes the thread has to take the same lock, so the thread can't
+/// be woken while it is still running on its stack.
+///
+pub struct DeferredUnlock {
+    lock: *const (),
+    unlock: unsafe fn(*const ()),
+}
+
+impl DeferredUnlock {
+    /// Defers the unlock of a held `IrqSpinlock`.
+    ///
+    /// # Safety
+    ///
+    /// The lock must be held by the calling thread, its guard must have been
+    /// leaked and the lock must outlive the switch away from the thread.
+    ///
+    pub unsafe fn irq_spin<T>(lock: &IrqSpinlock<T>) -> Self {
+        unsafe fn unlock<T>(lock: *const ()) {
+            (*(lock as *const IrqSpinlock<T>)).force_unlock();
+        }
+
+        Self {
+            lock: lock as *const IrqSpinlock<T> as *const (),
+            unlock: unlock::<T>,
+        }
+    }
+
+    fn run(self) {
+        unsafe { (self.unlock)(self.lock) };
+    }
+}
+
+/// The scheduler state of a single CPU.
+///
+/// The cells are only ever touched by the owning CPU with interrupts
+/// disabled, the run queue is shared with the other CPUs.
+///
+pub struct CpuScheduler {
+    current: Cell<Option<Arc<Thread>>>,
+    idle: Cell<Option<Arc<Thread>>>,
+
+    /// The thread this CPU just switched away from, it is requeued or
+    /// released once its stack is no longer in use.
+    previous: Cell<Option<Arc<Thread>>>,
+    requeue_previous: Cell<bool>,
+    deferred_unlock: Cell<Option<DeferredUnlock>>,
+
+    /// Set when the current thread should give up the CPU once the interrupt
+    /// handler returns.
+    need_resched: AtomicBool,
+    running: AtomicBool,
+
+    run_queue: Mutex<RunQueue>,
+}
+
+impl CpuScheduler {
+    pub const fn new() -> Self {
+        Self {
+            current: Cell::new(None),
+            idle: Cell::new(None),
+            previous: Cell::new(None),
+            requeue_previous: Cell::new(false),
+            deferred_unlock: Cell::new(None),
+            need_resched: AtomicBool::new(false),
+            running: AtomicBool::new(false),
+            run_queue: Mutex::new(RunQueue::new()),
+        }
+    }
+
+    fn current(&self) -> Arc<Thread> {
+        let current = self.current.take().expect("No thread is running");
+        self.current.set(Some(current.clone()));
+        current
+    }
+
+    fn idle_thread(&self) -> Arc<Thread> {
+        let idle = self.idle.take().expect("CPU has no idle thread");
+        self.idle.set(Some(idle.clone()));
+        idle
+    }
+}
+
+/// A thread waiting in `sleep_ns`.
+struct Sleeper {
+    wake_at: u64,
+    thread: Arc<Thread>,
+}
+
+impl PartialEq for Sleeper {
+    fn eq(&self, other: &Self) -> bool {
+        self.wake_at == other.wake_at
+    }
+}
+
+impl Eq for Sleeper {}
+
+impl PartialOrd for Sleeper {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Sleeper {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        // Reversed, so the heap yields the earliest wake up first
+        other.wake_at.cmp(&self.wake_at)
+    }
+}
+
+lazy_static! {
+    static ref SLEEPERS: IrqSpinlock<BinaryHeap<Sleeper>> = IrqSpinlock::new(BinaryHeap::new());
+}
+
+/// Threads that exited, their stacks are freed by the idle threads.
+static ZOMBIES: Mutex<Vec<Arc<Thread>>> = Mutex::new(Vec::new());
+
+static INIT: Once = Once::new();
+
+/// Starts scheduling on the calling CPU.
+///
+/// The code running on the CPU becomes a thread of its own, the CPU runs its
+/// idle thread whenever nothing else is ready.
+///
+pub fn init_cpu() {
+    INIT.call_once(|| {
+        irq::register_irq(lapic::TIMER_VECTOR, timer_tick);
+        irq::register_irq(RESCHEDULE_VECTOR, reschedule);
+    });
+
+    let cpu = this_cpu();
+    let scheduler = &cpu.scheduler;
+
+    // NOTE: The boot thread keeps running on the stack the CPU was started
+    //       on, which it doesn't own. That stack (direct mapped frames from
+    //       `allocate_stack`) is leaked once the thread exits, which happens
+    //       at most once per CPU.
+    let current = Thread::adopt_current(format!("boot-{}", cpu.id));
+    let idle = Thread::new_idle(
+        format!("idle-{}", cpu.id),
+        Box::new(idle_loop),
+        thread_start,
+    );
+
+    without_interrupts(|| {
+        scheduler.current.set(Some(Arc::new(current)));
+        scheduler.idle.set(Some(Arc::new(idle)));
+        scheduler.running.store(true, Ordering::Release);
+    });
+
+    lapic::start_periodic(TICK_HZ);
+
+    // Threads can only be queued on the CPU from now on
+    smp::mark_online();
+}
+
+/// Returns the thread running on the calling CPU.
+pub fn current() -> Arc<Thread> {
+    without_interrupts(|| this_cpu().scheduler.current())
+}
+
+/// Creates a thread that runs `f` and makes it ready to run.
+///
+/// # Arguments
+/// * `name` - The name of the thread, for diagnostics.
+/// * `f` - The function the thread runs, the thread exits once it returns.
+///
+pub fn spawn(name: impl Into<String>, f: impl FnOnce() + Send + 'static) -> Arc<Thread> {
+    spawn_with_priority(name, Priority::Normal, f)
+}
+
+/// Creates a thread with the given priority that runs `f` and makes it ready to run.
+///
+/// # Arguments
+/// * `name` - The name of the thread, for diagnostics.
+/// * `priority` - The priority of the thread.
+/// * `f` - The function the thread runs, the thread exits once it returns.
+///
+pub fn spawn_with_priority(
+    name: impl Into<String>,
+    priority: Priority,
+    f: impl FnOnce() + Send + 'static,
+) -> Arc<Thread> {
+    let thread = Arc::new(Thread::new(
+        name.into(),
+        priority,
+        Box::new(f),
+        thread_start,
+    ));
+
+    // New threads go to the CPU with the fewest ready threads
+    let cpu = online_cpus()
+        .min_by_key(|cpu| without_interrupts(|| cpu.scheduler.run_queue.lock().len()))
+        .map_or(0, |cpu| cpu.id);
+
+    trace!(
+        "Spawned thread {} ({}) on CPU {}",
+        thread.id(),
+        thread.name(),
+        cpu
+    );
+
+    thread.set_cpu(cpu);
+    enqueue(cpu, thread.clone());
+
+    thread
+}
+
+/// Gives up the CPU if another thread of at least the same priority is ready.
+pub fn yield_now() {
+    without_interrupts(|| unsafe { schedule(true) });
+}
+
+/// Blocks the calling thread for at least the given number of nanoseconds.
+///
+/// # Arguments
+/// * `ns` - The time to sleep in nanoseconds.
+///
+pub fn sleep_ns(ns: u64) {
+    let wake_at = clock::monotonic_ns().saturating_add(ns);
+
+    let mut sleepers = SLEEPERS.lock();
+
+    let current = current();
+    current.set_state(ThreadState::Blocked);
+    sleepers.push(Sleeper {
+        wake_at,
+        thread: current,
+    });
+
+    let (lock, _interrupts) = IrqSpinlockGuard::leak(sleepers);
+    unsafe { block(Some(DeferredUnlock::irq_spin(lock))) };
+}
+
+/// Switches away from the calling thread, which has to be marked as blocked already.
+///
+/// # Arguments
+/// * `unlock` - The lock protecting the structure the thread waits in, it is
+///              released once the thread is off the CPU.
+///
+/// # Safety
+///
+/// Interrupts have to be disabled. Something has to hold a reference to the
+/// thread and wake it up eventually.
+///
+pub unsafe fn block(unlock: Option<DeferredUnlock>) {
+    this_cpu().scheduler.deferred_unlock.set(unlock);
+    schedule(false);
+}
+
+/// Makes a blocked thread ready to run again.
+///
+/// Does nothing if the thread isn't blocked.
+///
+pub fn wake(thread: &Arc<Thread>) {
+    if thread.transition(ThreadState::Blocked, ThreadState::Ready) {
+        enqueue(thread.cpu(), thread.clone());
+    }
+}
+
+/// Ends the calling thread.
+pub fn exit() -> ! {
+    idt::disable();
+
+    let current = current();
+    current.set_state(ThreadState::Exited);
+
+    trace!("Thread {} ({}) exited", current.id(), current.name());
+    drop(current);
+
+    unsafe { schedule(false) };
+    unreachable!("Exited thread was scheduled again");
+}
+
+/// Switches to another thread if the timer or another CPU asked for it.
+///
+/// Called at the end of every interrupt, after the local APIC was acknowledged.
+///
+pub fn preempt() {
+    let scheduler = &this_cpu().scheduler;
+
+    if scheduler.running.load(Ordering::Acquire) && scheduler.need_resched.load(Ordering::Relaxed) {
+        unsafe { schedule(true) };
+    }
+}
+
+fn online_cpus() -> impl Iterator<Item = &'static percpu::PerCpu> {
+    let online = smp::online_mask();
+
+    (0..smp::MAX_CPUS)
+        .filter(move |id| online & (1 << id) != 0)
+        .filter_map(percpu::cpu)
+}
+
+/// Adds a ready thread to the run queue of the given CPU.
+fn enqueue(cpu: usize, thread: Arc<Thread>) {
+    let target = percpu::cpu(cpu).expect("Queueing a thread on an offline CPU");
+
+    without_interrupts(|| {
+        target.scheduler.run_queue.lock().push(thread);
+
+        if cpu == this_cpu().id {
+            target.scheduler.need_resched.store(true, Ordering::Relaxed);
+        } else {
+            ipi::send(Destination::Cpu(target.lapic_id), RESCHEDULE_VECTOR);
+        }
+    });
+}
+
+/// Takes a ready thread from another CPU.
+fn steal(own_id: usize) -> Option<Arc<Thread>> {
+    online_cpus()
+        .filter(|cpu| cpu.id != own_id)
+        .find_map(|cpu| cpu.scheduler.run_queue.try_lock()?.steal())
+}
+
+/// Picks the next thread for the calling CPU and switches to it.
+///
+/// # Arguments
+/// * `requeue` - Whether the current thread is still ready, `false` if it blocked or exited.
+///
+/// # Safety
+///
+/// Interrupts have to be disabled.
+///
+unsafe fn schedule(requeue: bool) {
+    let cpu = this_cpu();
+    let scheduler = &cpu.scheduler;
+
+    scheduler.need_resched.store(false, Ordering::Relaxed);
+
+    let current = scheduler.current.take().expect("No thread is running");
+    let requeue = requeue && !current.is_idle();
+
+    let next = {
+        let mut run_queue = scheduler.run_queue.lock();
+
+        // A ready thread keeps the CPU unless something at least as important waits
+        let keep_running = requeue
+            && run_queue
+                .highest_priority()
+                .map_or(true, |priority| priority < current.priority());
+
+        if keep_running {
+            None
+        } else {
+            run_queue.pop()
+        }
+    };
+
+    let next = match next {
+        Some(next) => Some(next),
+        None if requeue => None,
+        None => steal(cpu.id),
+    };
+
+    let next = match next {
+        Some(next) => next,
+        None if requeue || current.is_idle() => {
+            scheduler.current.set(Some(current));
+            return;
+        }
+        None => scheduler.idle_thread(),
+    };
+
+    if requeue {
+        current.set_state(ThreadState::Ready);
+    }
+
+    switch(current, next, requeue);
+}
+
+/// Switches from `current` to `next`.
+///
+/// Returns once `current` runs again.
+///
+/// # Safety
+///
+/// Interrupts have to be disabled and `current` must be the thread running
+/// on the calling CPU.
+///
+unsafe fn switch(current: Arc<Thread>, next: Arc<Thread>, requeue: bool) {
+    let cpu = this_cpu();
+    let scheduler = &cpu.scheduler;
+
+    // A stolen thread might still be switching away on its previous CPU
+    while next.on_cpu.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+
+    next.on_cpu.store(true, Ordering::Relaxed);
+    next.set_state(ThreadState::Running);
+    next.set_cpu(cpu.id);
+
+    if let Some(stack_top) = next.stack_top() {
+        cpu.set_kernel_stack(stack_top);
+    }
+
+    let from = current.context.get();
+    let to = next.context.get();
+
+    // Both threads stay alive through the references held by the CPU
+    scheduler.current.set(Some(next));
+    scheduler.previous.set(Some(current));
+    scheduler.requeue_previous.set(requeue);
+
+    context::switch(from, to);
+
+    finish_switch();
+}
+
+/// Releases the thread the calling CPU switched away from, it runs on the
+/// new thread right after every switch.
+fn finish_switch() {
+    let scheduler = &this_cpu().scheduler;
+
+    let Some(previous) = scheduler.previous.take() else {
+        return;
+    };
+
+    previous.on_cpu.store(false, Ordering::Release);
+
+    if scheduler.requeue_previous.get() {
+        scheduler.run_queue.lock().push(previous);
+    } else if previous.state() == ThreadState::Exited {
+        ZOMBIES.lock().push(previous);
+    }
+
+    if let Some(unlock) = scheduler.deferred_unlock.take() {
+        unlock.run();
+    }
+}
+
+/// The first code every spawned thread runs.
+extern "C" fn thread_start() -> ! {
+    finish_switch();
+    idt::enable();
+
+    let entry = current()
+        .take_entry()
+        .expect("Thread was started without an entry function");
+    entry();
+
+    exit();
+}
+
+fn idle_loop() {
+    loop {
+        // Freeing a stack needs the kernel page tables, which may be locked
+        // by a preempted thread, so it can't happen while switching
+        let zombies = without_interrupts(|| core::mem::take(&mut *ZOMBIES.lock()));
+        drop(zombies);
+
+        idt::disable();
+        unsafe { schedule(false) };
+
+        // Work queued from now on comes with an interrupt, which ends the halt
+        enable_and_halt();
+    }
+}
+
+fn timer_tick(_context: &InterruptContext) {
+    // Every CPU ticks, one of them waking the sleepers is enough
+    if let Some(mut sleepers) = SLEEPERS.try_lock() {
+        let now = clock::monotonic_ns();
+
+        while sleepers
+            .peek()
+            .map_or(false, |sleeper| sleeper.wake_at <= now)
+        {
+            let sleeper = sleepers.pop().unwrap();
+            wake(&sleeper.thread);
+        }
+    }
+
+    this_cpu()
+        .scheduler
+        .need_resched
+        .store(true, Ordering::Relaxed);
+}
+
+fn reschedule(_context: &InterruptContext) {
+    this_cpu()
+        .scheduler
+        .need_resched
+        .store(true, Ordering::Relaxed);
+}
@@ -0,0 +1,240 @@
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+};
+
+use alloc::{boxed::Box, string::String};
+use spin::Mutex;
+use x86::current::paging::BASE_PAGE_SIZE;
+
+use crate::arch::{
+    this_cpu,
+    x86_64::{context::Context, mmu},
+    VirtualAddress,
+};
+
+/// The size of the kernel stack of every thread in pages.
+pub const THREAD_STACK_PAGES: usize = 16;
+
+pub type ThreadId = u64;
+
+static NEXT_THREAD_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The scheduling state of a thread.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    /// Waiting in a run queue.
+    Ready,
+    /// Running on a CPU.
+    Running,
+    /// Waiting for something, it gets no CPU time until woken.
+    Blocked,
+    /// Finished, it is freed once the last reference is gone.
+    Exited,
+}
+
+/// How important a thread is, ready threads of a higher priority always run first.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Priority {
+    /// The number of priorities.
+    pub const COUNT: usize = 3;
+}
+
+/// A kernel stack with an unmapped guard page below it.
+pub struct KernelStack {
+    bottom: VirtualAddress,
+    pages: usize,
+}
+
+impl KernelStack {
+    /// Maps a new stack.
+    ///
+    /// # Arguments
+    /// * `pages` - The size of the stack in pages.
+    ///
+    pub fn new(pages: usize) -> Self {
+        Self {
+            bottom: mmu::map_kernel_stack(pages),
+            pages,
+        }
+    }
+
+    /// Returns the (16 byte aligned) end of the stack.
+    pub fn top(&self) -> u64 {
+        self.bottom.as_u64() + (self.pages * BASE_PAGE_SIZE) as u64
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        // The last reference to a thread is dropped by another thread, after
+        // the switch away from the stack completed
+        unsafe { mmu::unmap_kernel_stack(self.bottom, self.pages) };
+    }
+}
+
+/// A thread of execution in the kernel.
+pub struct Thread {
+    id: ThreadId,
+    name: String,
+    state: AtomicU8,
+    priority: Priority,
+
+    /// The CPU the thread last ran on, it gets woken up there.
+    cpu: AtomicUsize,
+
+    /// Idle threads only run when nothing else is ready and are never queued.
+    idle: bool,
+
+    /// Set from the moment a CPU switches to the thread until that CPU is
+    /// done switching away from it, the thread's stack is in use meanwhile.
+    pub(super) on_cpu: AtomicBool,
+
+    /// Only accessed by the scheduler while switching to or from the thread.
+    pub(super) context: UnsafeCell<Context>,
+
+    /// `None` for the threads adopting the boot stack of a CPU.
+    stack: Option<KernelStack>,
+
+    /// The function the thread starts with, taken once it runs.
+    entry: Mutex<Option<Box<dyn FnOnce() + Send>>>,
+}
+
+// The context is never touched by two CPUs at once, see `on_cpu`
+unsafe impl Sync for Thread {}
+
+impl Thread {
+    /// Creates a thread that starts with `entry` on a new stack.
+    ///
+    /// # Arguments
+    /// * `name` - The name of the thread, for diagnostics.
+    /// * `priority` - The priority of the thread.
+    /// * `entry` - The function the thread runs.
+    /// * `start` - The function the new stack returns into.
+    ///
+    pub(super) fn new(
+        name: String,
+        priority: Priority,
+        entry: Box<dyn FnOnce() + Send>,
+        start: extern "C" fn() -> !,
+    ) -> Self {
+        let stack = KernelStack::new(THREAD_STACK_PAGES);
+        let context = unsafe { Context::new(stack.top(), start) };
+
+        Self {
+            id: NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed),
+            name,
+            state: AtomicU8::new(ThreadState::Ready as u8),
+            priority,
+            cpu: AtomicUsize::new(0),
+            idle: false,
+            on_cpu: AtomicBool::new(false),
+            context: UnsafeCell::new(context),
+            stack: Some(stack),
+            entry: Mutex::new(Some(entry)),
+        }
+    }
+
+    /// Creates a thread for the code that is already running on the calling CPU.
+    ///
+    /// The thread has no stack of its own, the one it runs on is never freed.
+    ///
+    /// # Arguments
+    /// * `name` - The name of the thread, for diagnostics.
+    ///
+    pub(super) fn adopt_current(name: String) -> Self {
+        Self {
+            id: NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed),
+            name,
+            state: AtomicU8::new(ThreadState::Running as u8),
+            priority: Priority::Normal,
+            cpu: AtomicUsize::new(this_cpu().id),
+            idle: false,
+            on_cpu: AtomicBool::new(true),
+            context: UnsafeCell::new(Context::default()),
+            stack: None,
+            entry: Mutex::new(None),
+        }
+    }
+
+    /// Creates the idle thread of a CPU.
+    ///
+    /// # Arguments
+    /// * `name` - The name of the thread, for diagnostics.
+    /// * `entry` - The idle loop, it must never return.
+    /// * `start` - The function the new stack returns into.
+    ///
+    pub(super) fn new_idle(
+        name: String,
+        entry: Box<dyn FnOnce() + Send>,
+        start: extern "C" fn() -> !,
+    ) -> Self {
+        Self {
+            idle: true,
+            cpu: AtomicUsize::new(this_cpu().id),
+            ..Self::new(name, Priority::Low, entry, start)
+        }
+    }
+
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn state(&self) -> ThreadState {
+        match self.state.load(Ordering::Acquire) {
+            0 => ThreadState::Ready,
+            1 => ThreadState::Running,
+            2 => ThreadState::Blocked,
+            _ => ThreadState::Exited,
+        }
+    }
+
+    pub(crate) fn set_state(&self, state: ThreadState) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+
+    /// Changes the state from `from` to `to`, returns `false` if the thread wasn't in state `from`.
+    pub(super) fn transition(&self, from: ThreadState, to: ThreadState) -> bool {
+        self.state
+            .compare_exchange(from as u8, to as u8, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.idle
+    }
+
+    /// Returns the id of the CPU the thread last ran on.
+    pub fn cpu(&self) -> usize {
+        self.cpu.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn set_cpu(&self, cpu: usize) {
+        self.cpu.store(cpu, Ordering::Relaxed);
+    }
+
+    /// Returns the end of the kernel stack, `None` for adopted boot stacks.
+    pub fn stack_top(&self) -> Option<u64> {
+        self.stack.as_ref().map(KernelStack::top)
+    }
+
+    pub(super) fn take_entry(&self) -> Option<Box<dyn FnOnce() + Send>> {
+        self.entry.lock().take()
+    }
+}